@@ -1,6 +1,8 @@
-#![allow(dead_code)]
-use crate::scanner::token::{self, Token, TokenType};
-use crate::vm::bytecode::{self, ByteCode, Opcode};
+use std::rc::Rc;
+
+use crate::scanner::token::{Token, TokenType};
+use crate::vm::bytecode::{ByteCode, Opcode};
+use crate::vm::value::{Function, Value};
 
 struct TokenStream<'a> {
     tokens: &'a Vec<Token>,
@@ -13,244 +15,998 @@ impl<'a> TokenStream<'a> {
     fn peek(&self) -> &Token {
         &self.tokens[self.cursor]
     }
+    fn previous(&self) -> Option<&Token> {
+        self.cursor
+            .checked_sub(1)
+            .map(|cursor| &self.tokens[cursor])
+    }
+    /// keeps returning the `Eof` token once the end is reached
     fn next(&mut self) -> Token {
         let retval = self.tokens[self.cursor].clone();
-        self.cursor += 1;
+        if !is_end(&retval) {
+            self.cursor += 1;
+        }
         retval
     }
 }
 
-pub fn compile(tokens: &Vec<Token>) -> ByteCode {
-    let mut tokens = TokenStream::from(tokens);
-    if is_end(tokens.peek()) {
-        return emit_end(&tokens.next());
+/// local slots are addressed with a one byte operand
+const LOCALS_LIMIT: usize = 256;
+const ARGUMENTS_LIMIT: usize = 255;
+/// upvalues are addressed with a one byte operand as well
+const UPVALUES_LIMIT: usize = 256;
+/// constants past the first 256 of a pool need the long form of the instruction,
+/// whose operand is 24 bits wide
+const LONG_CONSTANTS_LIMIT: usize = 1 << 24;
+
+/// Compiles the program into the function run by the VM at the top level.
+/// Returns `None` when compile errors were reported.
+pub fn compile(tokens: &Vec<Token>) -> Option<Function> {
+    let mut compiler = Compiler::new(tokens);
+    compiler.program();
+    let script = compiler.functions.pop()?;
+    if compiler.had_error {
+        return None;
     }
-    return expression_parser(&mut tokens);
+    Some(script.function)
 }
 
-fn expression_parser(tokens: &mut TokenStream) -> ByteCode {
-    let expr_end = TokenType::Semicolon;
-    let mut code = ByteCode::new();
-    loop {
-        if tokens.peek().token_type == expr_end {
-            let token = tokens.next();
-            code.write_code(Opcode::Ret as u8, token.line as u32);
-            return code;
-        } else if is_end(tokens.peek()) {
-            panic!("expression must end with a semicolon");
-        } else if is_number(tokens.peek()) || tokens.peek().token_type == TokenType::LeftParen {
-            return binary_parser(tokens, expr_end);
-        } else if is_string(tokens.peek()) {
-            code = string_parser(tokens);
-        } else if is_op(tokens.peek()) {
-            emit_op(&mut code, &tokens.next());
-        } else {
-            dbg!(&tokens.peek());
-            panic!("not implemented");
+struct Local {
+    name: String,
+    /// `None` while the initializer of the variable is being compiled
+    depth: Option<usize>,
+    /// captured locals are moved off the stack when their scope ends
+    is_captured: bool,
+}
+
+/// Variable of an enclosing function captured by the function being compiled
+struct Upvalue {
+    /// slot of the local in the directly enclosing function when `is_local`,
+    /// otherwise index of one of its own upvalues
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
+    Method,
+    /// `init` methods return `this`
+    Initializer,
+}
+
+/// State of a function whose body is being compiled
+struct FunctionState {
+    function: Function,
+    function_type: FunctionType,
+    /// mirrors the stack slots the locals will occupy at runtime
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(name: String, function_type: FunctionType) -> Self {
+        // the first slot holds the function being called, or the receiver in methods
+        let slot_zero = match function_type {
+            FunctionType::Method | FunctionType::Initializer => "this".to_string(),
+            FunctionType::Script | FunctionType::Function => String::new(),
+        };
+        FunctionState {
+            function: Function::new(name),
+            function_type,
+            locals: vec![Local {
+                name: slot_zero,
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
         }
     }
 }
 
-fn string_parser(tokens: &mut TokenStream) -> ByteCode {
-    let mut code = ByteCode::new();
-    emit_string(&mut code, &tokens.next());
-    loop {
-        let next = tokens.peek();
-        match next.token_type {
-            TokenType::Plus => {
-                tokens.next();
-                let tok = tokens.next();
-                match tok.token_type {
-                    TokenType::String => {
-                        emit_string(&mut code, &tok);
-                        code.write_code(Opcode::Add as u8, tok.line as u32);
-                    }
-                    _ => {
-                        panic!("expected a string after a +")
-                    }
-                }
+/// State of a class whose body is being compiled
+struct ClassState {
+    has_superclass: bool,
+}
+
+/// Single pass compiler emitting straight into the chunk of the function being compiled.
+/// Expressions are parsed with a Pratt parser driven by the rules of `get_rule`.
+struct Compiler<'a> {
+    tokens: TokenStream<'a>,
+    /// the last one is being compiled, the ones before it enclose it
+    functions: Vec<FunctionState>,
+    /// the last one is being compiled, the ones before it enclose it
+    classes: Vec<ClassState>,
+    had_error: bool,
+    /// suppresses the errors cascading from the first one until the next statement
+    panic_mode: bool,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(tokens: &'a Vec<Token>) -> Self {
+        Compiler {
+            tokens: TokenStream::from(tokens),
+            functions: vec![FunctionState::new(
+                "script".to_string(),
+                FunctionType::Script,
+            )],
+            classes: Vec::new(),
+            had_error: false,
+            panic_mode: false,
+        }
+    }
+
+    fn current(&mut self) -> &mut FunctionState {
+        self.functions
+            .last_mut()
+            .expect("there is always a function being compiled")
+    }
+
+    fn code(&mut self) -> &mut ByteCode {
+        &mut self.current().function.code
+    }
+
+    fn program(&mut self) {
+        // the value of a trailing expression statement is the result of the program
+        let mut has_result = false;
+        while !is_end(self.tokens.peek()) {
+            has_result = self.declaration(true);
+        }
+        let end = self.tokens.next();
+        if !has_result {
+            self.code().write_code(Opcode::Nil as u8, end.line as u32);
+        }
+        self.code().write_code(Opcode::Ret as u8, end.line as u32);
+    }
+
+    /// returns whether the declaration left its value on the stack,
+    /// which only a top level expression statement ending the program does
+    fn declaration(&mut self, is_top_level: bool) -> bool {
+        let has_result = match self.tokens.peek().token_type {
+            TokenType::Var => {
+                self.var_declaration();
+                false
             }
-            TokenType::EqualEqual => {
-                tokens.next();
-                let tok = tokens.next();
-                match tok.token_type {
-                    TokenType::String => {
-                        emit_string(&mut code, &tok);
-                        code.write_code(Opcode::Equal as u8, tok.line as u32);
-                    }
-                    _ => {
-                        panic!("expected a string after a +")
-                    }
-                }
-                break;
+            TokenType::Fun => {
+                self.fun_declaration();
+                false
             }
-            _ => break,
+            TokenType::Class => {
+                self.class_declaration();
+                false
+            }
+            _ => self.statement(is_top_level),
+        };
+        if self.panic_mode {
+            self.synchronize();
         }
+        has_result
     }
-    code
-}
 
-fn paren_parser(tokens: &mut TokenStream) -> ByteCode {
-    if tokens.peek().token_type == TokenType::LeftParen {
-        tokens.next();
-        let mut code = paren_parser(tokens);
-        if tokens.peek().token_type == TokenType::RightParen {
-            tokens.next();
-            return code;
-        }
-        code = pratt_parser(
-            code,
-            &Token::new(TokenType::Number, "".to_string(), 0),
-            tokens,
-        );
-        tokens.next();
-        return code;
+    fn statement(&mut self, is_top_level: bool) -> bool {
+        match self.tokens.peek().token_type {
+            TokenType::LeftBrace => {
+                self.tokens.next();
+                self.begin_scope();
+                self.block();
+                self.end_scope();
+                false
+            }
+            TokenType::If => {
+                self.if_statement();
+                false
+            }
+            TokenType::While => {
+                self.while_statement();
+                false
+            }
+            TokenType::For => {
+                self.for_statement();
+                false
+            }
+            TokenType::Return => {
+                self.return_statement();
+                false
+            }
+            TokenType::Print => {
+                self.print_statement();
+                false
+            }
+            _ => self.expression_statement(is_top_level),
+        }
     }
-    let code = binary_parser(tokens, TokenType::RightParen);
-    tokens.next();
-    code
-}
 
-fn binary_parser(tokens: &mut TokenStream, end_token: TokenType) -> ByteCode {
-    let token = tokens.next();
-    let mut left = if token.token_type == TokenType::LeftParen {
-        paren_parser(tokens)
-    } else if token.token_type == TokenType::Number {
-        emit_number(&token)
-    } else {
-        panic!("invalid first token {} in binary parser", token.token_type);
-    };
-    loop {
-        left = pratt_parser(
-            left,
-            &Token::new(TokenType::Number, "".to_string(), 0),
-            tokens,
-        );
-        if tokens.peek().token_type == end_token {
-            return left;
+    fn print_statement(&mut self) {
+        let keyword = self.tokens.next();
+        self.expression();
+        self.consume(TokenType::Semicolon, "expected ';' after value");
+        self.code()
+            .write_code(Opcode::Print as u8, keyword.line as u32);
+    }
+
+    fn if_statement(&mut self) {
+        let keyword = self.tokens.next();
+        self.consume(TokenType::LeftParen, "expected '(' after 'if'");
+        self.expression();
+        self.consume(TokenType::RightParen, "expected ')' after condition");
+
+        let line = keyword.line as u32;
+        let then_jump = emit_jump(self.code(), Opcode::JumpIfFalse, line);
+        self.code().write_code(Opcode::Pop as u8, line);
+        self.statement(false);
+        let else_jump = emit_jump(self.code(), Opcode::Jump, line);
+        self.patch_jump(then_jump, &keyword);
+        self.code().write_code(Opcode::Pop as u8, line);
+        if self.tokens.peek().token_type == TokenType::Else {
+            self.tokens.next();
+            self.statement(false);
         }
-        if is_end(tokens.peek()) {
-            if end_token != TokenType::Eof {
-                panic!("missing {}", end_token);
+        self.patch_jump(else_jump, &keyword);
+    }
+
+    fn while_statement(&mut self) {
+        let keyword = self.tokens.next();
+        let line = keyword.line as u32;
+        let loop_start = self.code().code.len();
+        self.consume(TokenType::LeftParen, "expected '(' after 'while'");
+        self.expression();
+        self.consume(TokenType::RightParen, "expected ')' after condition");
+
+        let exit_jump = emit_jump(self.code(), Opcode::JumpIfFalse, line);
+        self.code().write_code(Opcode::Pop as u8, line);
+        self.statement(false);
+        self.emit_loop(loop_start, &keyword);
+        self.patch_jump(exit_jump, &keyword);
+        self.code().write_code(Opcode::Pop as u8, line);
+    }
+
+    fn for_statement(&mut self) {
+        let keyword = self.tokens.next();
+        let line = keyword.line as u32;
+        // a variable declared in the initializer is scoped to the loop
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "expected '(' after 'for'");
+        match self.tokens.peek().token_type {
+            TokenType::Semicolon => {
+                self.tokens.next();
+            }
+            TokenType::Var => self.var_declaration(),
+            _ => {
+                self.expression_statement(false);
             }
-            return left;
         }
+
+        let mut loop_start = self.code().code.len();
+        let mut exit_jump = None;
+        if self.tokens.peek().token_type != TokenType::Semicolon {
+            self.expression();
+            exit_jump = Some(emit_jump(self.code(), Opcode::JumpIfFalse, line));
+            self.code().write_code(Opcode::Pop as u8, line);
+        }
+        self.consume(TokenType::Semicolon, "expected ';' after loop condition");
+
+        if self.tokens.peek().token_type != TokenType::RightParen {
+            // the increment is emitted before the body, so jump over it on the way in
+            let body_jump = emit_jump(self.code(), Opcode::Jump, line);
+            let increment_start = self.code().code.len();
+            self.expression();
+            self.code().write_code(Opcode::Pop as u8, line);
+            self.emit_loop(loop_start, &keyword);
+            loop_start = increment_start;
+            self.patch_jump(body_jump, &keyword);
+        }
+        self.consume(TokenType::RightParen, "expected ')' after for clauses");
+
+        self.statement(false);
+        self.emit_loop(loop_start, &keyword);
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump, &keyword);
+            self.code().write_code(Opcode::Pop as u8, line);
+        }
+        self.end_scope();
     }
-}
 
-fn pratt_parser(left: ByteCode, left_token: &Token, tokens: &mut TokenStream) -> ByteCode {
-    let op = tokens.next();
-    if is_end(&op) || !is_binary_operator(&op) {
-        return left;
+    fn return_statement(&mut self) {
+        let keyword = self.tokens.next();
+        let line = keyword.line as u32;
+        if self.current().function_type == FunctionType::Script {
+            self.error_at(&keyword, "can't return from top-level code");
+        }
+        if self.tokens.peek().token_type == TokenType::Semicolon {
+            self.tokens.next();
+            self.emit_return(line);
+            return;
+        }
+        if self.current().function_type == FunctionType::Initializer {
+            self.error_at(&keyword, "can't return a value from an initializer");
+        }
+        self.expression();
+        self.consume(TokenType::Semicolon, "expected ';' after return value");
+        self.code().write_code(Opcode::Ret as u8, line);
+    }
+
+    /// returns `this` from initializers and nil from any other function
+    fn emit_return(&mut self, line: u32) {
+        if self.current().function_type == FunctionType::Initializer {
+            self.code().write_code(Opcode::GetLocal as u8, line);
+            self.code().write_code(0, line);
+        } else {
+            self.code().write_code(Opcode::Nil as u8, line);
+        }
+        self.code().write_code(Opcode::Ret as u8, line);
     }
-    let right_token = tokens.next();
-    if is_end(&right_token) {
-        eprintln!(
-            "[{}] binary operator '{}' needs a valid right operand",
-            right_token.line, op.lexeme
+
+    /// jumps backwards to `loop_start`
+    fn emit_loop(&mut self, loop_start: usize, token: &Token) {
+        let line = token.line as u32;
+        self.code().write_code(Opcode::Loop as u8, line);
+        // the offset also covers the operand of the loop instruction itself
+        let offset = self.code().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error_at(token, "loop body too large");
+        }
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.code().write_code(high, line);
+        self.code().write_code(low, line);
+    }
+
+    /// points the jump whose operand is at `offset` to the end of the code
+    fn patch_jump(&mut self, offset: usize, token: &Token) {
+        if !patch_jump(self.code(), offset) {
+            self.error_at(token, "too much code to jump over");
+        }
+    }
+
+    fn block(&mut self) {
+        while !is_end(self.tokens.peek()) && self.tokens.peek().token_type != TokenType::RightBrace
+        {
+            self.declaration(false);
+        }
+        self.consume(TokenType::RightBrace, "expected '}' after block");
+    }
+
+    fn var_declaration(&mut self) {
+        self.tokens.next();
+        let name = self.consume(
+            TokenType::Identifier,
+            "expected a variable name after 'var'",
         );
-        panic!()
-    }
-    if right_token.token_type != TokenType::LeftParen
-        && left_token.token_type != right_token.token_type
-    {
-        eprintln!(
-            "[{}] binary operator '{}' needs the left and right operand to be of same type",
-            right_token.line, op.lexeme
+        self.declare_variable(&name);
+        if self.tokens.peek().token_type == TokenType::Equal {
+            self.tokens.next();
+            self.expression();
+        } else {
+            self.code().write_code(Opcode::Nil as u8, name.line as u32);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "expected ';' after variable declaration",
         );
-        panic!()
+        self.define_variable(&name);
     }
-    let mut right = {
-        if right_token.token_type == TokenType::LeftParen {
-            paren_parser(tokens)
-        } else if is_number(left_token) {
-            emit_number(&right_token)
+
+    fn fun_declaration(&mut self) {
+        self.tokens.next();
+        let name = self.consume(TokenType::Identifier, "expected a function name");
+        // initialized right away so that the function can call itself recursively
+        self.declare_variable(&name);
+        self.mark_initialized();
+        self.function(&name, FunctionType::Function);
+        self.define_variable(&name);
+    }
+
+    /// compiles the parameters and body into a new function object
+    /// and emits the instruction loading it
+    fn function(&mut self, name: &Token, function_type: FunctionType) {
+        self.functions
+            .push(FunctionState::new(name.lexeme.clone(), function_type));
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "expected '(' after function name");
+        if self.tokens.peek().token_type != TokenType::RightParen {
+            loop {
+                self.current().function.arity += 1;
+                if self.current().function.arity > ARGUMENTS_LIMIT {
+                    let token = self.tokens.peek().clone();
+                    self.error_at(&token, "can't have more than 255 parameters");
+                }
+                let param = self.consume(TokenType::Identifier, "expected a parameter name");
+                self.declare_variable(&param);
+                self.mark_initialized();
+                if self.tokens.peek().token_type != TokenType::Comma {
+                    break;
+                }
+                self.tokens.next();
+            }
+        }
+        self.consume(TokenType::RightParen, "expected ')' after parameters");
+        self.consume(TokenType::LeftBrace, "expected '{' before function body");
+        self.block();
+
+        let line = self.tokens.previous().map_or(name.line, |token| token.line) as u32;
+        self.emit_return(line);
+        // the scope isn't ended, the frame is discarded together with its locals
+        let mut state = self.functions.pop().expect("the function was pushed above");
+        state.function.upvalue_count = state.upvalues.len();
+        let line = name.line as u32;
+        let index = self
+            .code()
+            .add_constant(Value::Function(Rc::new(state.function)));
+        self.emit_operand(Opcode::Closure, index, name);
+        let code = self.code();
+        for upvalue in state.upvalues {
+            code.write_code(upvalue.is_local as u8, line);
+            code.write_code(upvalue.index, line);
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.tokens.next();
+        let name = self.consume(TokenType::Identifier, "expected a class name");
+        let name_constant = self.identifier_constant(&name);
+        self.declare_variable(&name);
+        self.emit_operand(Opcode::Class, name_constant, &name);
+        self.define_variable(&name);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+        if self.tokens.peek().token_type == TokenType::Less {
+            self.tokens.next();
+            let superclass = self.consume(TokenType::Identifier, "expected a superclass name");
+            if superclass.lexeme == name.lexeme {
+                self.error_at(&superclass, "a class can't inherit from itself");
+            }
+            self.named_variable(&superclass, false);
+            // the superclass is kept in a local enclosing the methods, where `super` finds it
+            self.begin_scope();
+            let super_token = synthetic_token(TokenType::Super, "super", superclass.line);
+            self.declare_local(&super_token);
+            self.mark_initialized();
+            self.named_variable(&name, false);
+            self.code()
+                .write_code(Opcode::Inherit as u8, superclass.line as u32);
+            self.current_class().has_superclass = true;
+        }
+
+        // the class is loaded again so that `Method` can find it below the method
+        self.named_variable(&name, false);
+        self.consume(TokenType::LeftBrace, "expected '{' before class body");
+        while !is_end(self.tokens.peek()) && self.tokens.peek().token_type != TokenType::RightBrace
+        {
+            self.method();
+        }
+        let brace = self.consume(TokenType::RightBrace, "expected '}' after class body");
+        self.code().write_code(Opcode::Pop as u8, brace.line as u32);
+        let class = self.classes.pop().expect("the class was pushed above");
+        if class.has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn current_class(&mut self) -> &mut ClassState {
+        self.classes
+            .last_mut()
+            .expect("only called inside a class body")
+    }
+
+    fn method(&mut self) {
+        let name = self.consume(TokenType::Identifier, "expected a method name");
+        let function_type = if name.lexeme == "init" {
+            FunctionType::Initializer
         } else {
-            panic!("not implemented")
+            FunctionType::Method
+        };
+        self.function(&name, function_type);
+        let name_constant = self.identifier_constant(&name);
+        self.emit_operand(Opcode::Method, name_constant, &name);
+    }
+
+    /// locals are declared on the compiler, globals are looked up by name at runtime
+    fn declare_variable(&mut self, name: &Token) {
+        if self.current().scope_depth > 0 {
+            self.declare_local(name);
+        }
+    }
+
+    /// makes the variable, whose value is on top of the stack, available
+    fn define_variable(&mut self, name: &Token) {
+        if self.current().scope_depth > 0 {
+            // the value of a local simply stays in its stack slot
+            self.mark_initialized();
+            return;
+        }
+        let global = self.identifier_constant(name);
+        self.emit_operand(Opcode::DefineGlobal, global, name);
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.current();
+        if state.scope_depth == 0 {
+            return;
         }
-    };
-    loop {
-        let next_op = tokens.peek();
-        if is_end(next_op) || next_op.token_type == TokenType::RightParen {
-            return ByteCode::merge_binary(&left, &right, opcode_from_op(&op), op.line as u32);
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
         }
-        if get_binding_power(&(op.token_type)).right_operand
-            >= get_binding_power(&(next_op.token_type)).left_operand
+    }
+
+    fn declare_local(&mut self, name: &Token) {
+        let state = self.current();
+        let is_redeclared = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth == state.scope_depth))
+            .any(|local| local.name == name.lexeme);
+        if is_redeclared {
+            self.error_at(name, "already a variable with this name in this scope");
+            return;
+        }
+        if state.locals.len() >= LOCALS_LIMIT {
+            self.error_at(name, "too many local variables in function");
+            return;
+        }
+        state.locals.push(Local {
+            name: name.lexeme.clone(),
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    /// looks the variable up among the locals of the function at `level` in `functions`
+    fn resolve_local(&mut self, level: usize, name: &Token) -> Option<u8> {
+        let state = &self.functions[level];
+        let slot = state
+            .locals
+            .iter()
+            .rposition(|local| local.name == name.lexeme)?;
+        if state.locals[slot].depth.is_none() {
+            self.error_at(name, "can't read local variable in its own initializer");
+        }
+        Some(slot as u8)
+    }
+
+    /// looks the variable up in the enclosing functions, capturing it in every function
+    /// between the one declaring it and the one at `level`
+    fn resolve_upvalue(&mut self, level: usize, name: &Token) -> Option<u8> {
+        let enclosing = level.checked_sub(1)?;
+        if let Some(slot) = self.resolve_local(enclosing, name) {
+            self.functions[enclosing].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(level, slot, true, name));
+        }
+        let index = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(level, index, false, name))
+    }
+
+    fn add_upvalue(&mut self, level: usize, index: u8, is_local: bool, name: &Token) -> u8 {
+        let upvalues = &mut self.functions[level].upvalues;
+        // closures referring to the same variable several times capture it once
+        if let Some(existing) = upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
         {
-            return ByteCode::merge_binary(&left, &right, opcode_from_op(&op), op.line as u32);
+            return existing as u8;
+        }
+        if upvalues.len() >= UPVALUES_LIMIT {
+            self.error_at(name, "too many closure variables in function");
+            return 0;
+        }
+        upvalues.push(Upvalue { index, is_local });
+        (upvalues.len() - 1) as u8
+    }
+
+    fn begin_scope(&mut self) {
+        self.current().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let line = self.tokens.peek().line as u32;
+        let state = self.current();
+        state.scope_depth -= 1;
+        while let Some(local) = state.locals.last() {
+            if local.depth.is_some_and(|depth| depth <= state.scope_depth) {
+                break;
+            }
+            let opcode = if local.is_captured {
+                Opcode::CloseUpvalue
+            } else {
+                Opcode::Pop
+            };
+            state.locals.pop();
+            state.function.code.write_code(opcode as u8, line);
+        }
+    }
+
+    fn expression_statement(&mut self, is_top_level: bool) -> bool {
+        self.expression();
+        let semicolon = self.consume(TokenType::Semicolon, "expected ';' after expression");
+        if is_top_level && is_end(self.tokens.peek()) {
+            return true;
+        }
+        self.code()
+            .write_code(Opcode::Pop as u8, semicolon.line as u32);
+        false
+    }
+
+    fn consume(&mut self, token_type: TokenType, message: &str) -> Token {
+        let token = self.tokens.peek().clone();
+        if token.token_type == token_type {
+            return self.tokens.next();
+        }
+        self.error_at(&token, message);
+        token
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.had_error = true;
+        if is_end(token) {
+            eprintln!("[line {}] compile error at end: {}", token.line, message);
+        } else {
+            eprintln!(
+                "[line {}] compile error at '{}': {}",
+                token.line, token.lexeme, message
+            );
+        }
+    }
+
+    /// skips to the start of the next statement
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        loop {
+            if let Some(previous) = self.tokens.previous() {
+                if previous.token_type == TokenType::Semicolon {
+                    return;
+                }
+            }
+            match self.tokens.peek().token_type {
+                TokenType::Eof
+                | TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => {
+                    self.tokens.next();
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    /// compiles an expression whose operators bind at least as tightly as `precedence`
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        let token = self.tokens.next();
+        let prefix = match get_rule(&token.token_type).prefix {
+            Some(prefix) => prefix,
+            None => {
+                self.error_at(&token, "expected an expression");
+                return;
+            }
+        };
+        // `a * b = c` must not compile `b = c`, so only a low precedence operand can be assigned
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix(self, &token, can_assign);
+        while precedence <= get_rule(&self.tokens.peek().token_type).precedence {
+            let operator = self.tokens.next();
+            let infix = get_rule(&operator.token_type)
+                .infix
+                .expect("tokens with a precedence have an infix rule");
+            infix(self, &operator, can_assign);
+        }
+        let next = self.tokens.peek().clone();
+        if can_assign && next.token_type == TokenType::Equal {
+            self.error_at(&next, "invalid assignment target");
+        }
+    }
+
+    fn grouping(&mut self, _paren: &Token, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightParen, "expected ')' after expression");
+    }
+
+    fn number(&mut self, token: &Token, _can_assign: bool) {
+        let value = token
+            .lexeme
+            .parse::<f64>()
+            .expect("the scanner only emits valid numbers");
+        let index = self.code().add_constant(Value::Num(value));
+        self.emit_operand(Opcode::Constant, index, token);
+    }
+
+    fn string(&mut self, token: &Token, _can_assign: bool) {
+        let index = self.identifier_constant(token);
+        self.emit_operand(Opcode::Constant, index, token);
+    }
+
+    fn literal(&mut self, token: &Token, _can_assign: bool) {
+        let opcode = match token.token_type {
+            TokenType::True => Opcode::True,
+            TokenType::False => Opcode::False,
+            _ => Opcode::Nil,
+        };
+        self.code().write_code(opcode as u8, token.line as u32);
+    }
+
+    fn unary(&mut self, operator: &Token, _can_assign: bool) {
+        // unary operators nest, `--a` negates twice
+        self.parse_precedence(Precedence::Unary);
+        let opcode = match operator.token_type {
+            TokenType::Minus => Opcode::Neg,
+            _ => Opcode::Not,
+        };
+        self.code().write_code(opcode as u8, operator.line as u32);
+    }
+
+    fn variable(&mut self, name: &Token, can_assign: bool) {
+        self.named_variable(name, can_assign);
+    }
+
+    fn binary(&mut self, operator: &Token, _can_assign: bool) {
+        // the right operand binds one level tighter, which makes operators left associative
+        let precedence = get_rule(&operator.token_type).precedence.next();
+        self.parse_precedence(precedence);
+        self.code()
+            .write_code(opcode_from_op(operator) as u8, operator.line as u32);
+    }
+
+    /// `and` and `or` skip evaluating the right operand when the left one decides the result
+    fn and(&mut self, operator: &Token, _can_assign: bool) {
+        let line = operator.line as u32;
+        let end_jump = emit_jump(self.code(), Opcode::JumpIfFalse, line);
+        self.code().write_code(Opcode::Pop as u8, line);
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump, operator);
+    }
+
+    fn or(&mut self, operator: &Token, _can_assign: bool) {
+        let line = operator.line as u32;
+        let else_jump = emit_jump(self.code(), Opcode::JumpIfFalse, line);
+        let end_jump = emit_jump(self.code(), Opcode::Jump, line);
+        self.patch_jump(else_jump, operator);
+        self.code().write_code(Opcode::Pop as u8, line);
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump, operator);
+    }
+
+    fn call(&mut self, paren: &Token, _can_assign: bool) {
+        let arg_count = self.argument_list(paren);
+        self.code()
+            .write_code(Opcode::Call as u8, paren.line as u32);
+        self.code().write_code(arg_count, paren.line as u32);
+    }
+
+    /// property access, assignment, or a method call compiled into a single `Invoke`
+    fn dot(&mut self, _dot: &Token, can_assign: bool) {
+        let name = self.consume(TokenType::Identifier, "expected a property name after '.'");
+        let line = name.line as u32;
+        let name_constant = self.identifier_constant(&name);
+        match self.tokens.peek().token_type {
+            TokenType::Equal if can_assign => {
+                self.tokens.next();
+                self.expression();
+                self.emit_operand(Opcode::SetProperty, name_constant, &name);
+            }
+            TokenType::LeftParen => {
+                let paren = self.tokens.next();
+                let arg_count = self.argument_list(&paren);
+                self.emit_operand(Opcode::Invoke, name_constant, &name);
+                self.code().write_code(arg_count, line);
+            }
+            _ => self.emit_operand(Opcode::GetProperty, name_constant, &name),
+        }
+    }
+
+    fn this(&mut self, keyword: &Token, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error_at(keyword, "can't use 'this' outside of a class");
+        }
+        self.named_variable(keyword, false);
+    }
+
+    /// `super.name` looks the method up starting from the superclass of the enclosing class
+    fn super_access(&mut self, keyword: &Token, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error_at(keyword, "can't use 'super' outside of a class"),
+            Some(class) if !class.has_superclass => {
+                self.error_at(keyword, "can't use 'super' in a class with no superclass")
+            }
+            Some(_) => {}
+        }
+        self.consume(TokenType::Dot, "expected '.' after 'super'");
+        let name = self.consume(TokenType::Identifier, "expected a superclass method name");
+        let line = name.line as u32;
+        let this = synthetic_token(TokenType::This, "this", keyword.line);
+        let super_token = synthetic_token(TokenType::Super, "super", keyword.line);
+
+        self.named_variable(&this, false);
+        let name_constant = self.identifier_constant(&name);
+        if self.tokens.peek().token_type == TokenType::LeftParen {
+            let paren = self.tokens.next();
+            let arg_count = self.argument_list(&paren);
+            self.named_variable(&super_token, false);
+            self.emit_operand(Opcode::SuperInvoke, name_constant, &name);
+            self.code().write_code(arg_count, line);
+        } else {
+            self.named_variable(&super_token, false);
+            self.emit_operand(Opcode::GetSuper, name_constant, &name);
+        }
+    }
+
+    /// compiles the arguments up to the closing paren and returns how many there are
+    fn argument_list(&mut self, paren: &Token) -> u8 {
+        let mut arg_count = 0;
+        if self.tokens.peek().token_type != TokenType::RightParen {
+            loop {
+                self.expression();
+                arg_count += 1;
+                if arg_count > ARGUMENTS_LIMIT {
+                    self.error_at(paren, "can't have more than 255 arguments");
+                }
+                if self.tokens.peek().token_type != TokenType::Comma {
+                    break;
+                }
+                self.tokens.next();
+            }
+        }
+        self.consume(TokenType::RightParen, "expected ')' after arguments");
+        arg_count as u8
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let level = self.functions.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(level, name) {
+            (Opcode::GetLocal, Opcode::SetLocal, slot as usize)
+        } else if let Some(index) = self.resolve_upvalue(level, name) {
+            (Opcode::GetUpvalue, Opcode::SetUpvalue, index as usize)
+        } else {
+            (
+                Opcode::GetGlobal,
+                Opcode::SetGlobal,
+                self.identifier_constant(name),
+            )
+        };
+        if can_assign && self.tokens.peek().token_type == TokenType::Equal {
+            self.tokens.next();
+            self.expression();
+            self.emit_operand(set_op, arg, name);
+        } else {
+            self.emit_operand(get_op, arg, name);
+        }
+    }
+
+    /// adds the lexeme to the constants of the current chunk, names are looked up by string
+    fn identifier_constant(&mut self, token: &Token) -> usize {
+        self.code()
+            .add_constant(Value::Str(Rc::from(token.lexeme.as_str())))
+    }
+
+    /// writes the instruction with a one byte operand,
+    /// or its long form when the operand doesn't fit in a byte
+    fn emit_operand(&mut self, opcode: Opcode, operand: usize, token: &Token) {
+        let line = token.line as u32;
+        if let Ok(operand) = u8::try_from(operand) {
+            self.code().write_code(opcode as u8, line);
+            self.code().write_code(operand, line);
+            return;
+        }
+        match opcode.long_form() {
+            Some(long_opcode) if operand < LONG_CONSTANTS_LIMIT => {
+                let [_, high, middle, low] = (operand as u32).to_be_bytes();
+                self.code().write_code(long_opcode as u8, line);
+                self.code().write_code(high, line);
+                self.code().write_code(middle, line);
+                self.code().write_code(low, line);
+            }
+            _ => self.error_at(token, "too many constants in one chunk"),
         }
-        right = pratt_parser(right, &right_token, tokens);
     }
 }
 
-struct BindingPower {
-    left_operand: f32,
-    right_operand: f32,
+/// Operator precedences from the loosest to the tightest binding
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Unary,
+    Call,
+    Primary,
 }
-fn get_binding_power(operator: &TokenType) -> BindingPower {
-    match operator {
-        TokenType::Semicolon => BindingPower {
-            left_operand: -2.0,
-            right_operand: -2.0,
-        },
-        TokenType::EqualEqual
-        | TokenType::BangEqual
-        | TokenType::Greater
-        | TokenType::GreaterEqual
-        | TokenType::Less
-        | TokenType::LessEqual => BindingPower {
-            left_operand: -1.1,
-            right_operand: -1.0,
-        },
-        TokenType::Plus => BindingPower {
-            left_operand: 1.0,
-            right_operand: 1.1,
-        },
-        TokenType::Minus => BindingPower {
-            left_operand: 2.0,
-            right_operand: 2.1,
-        },
-        TokenType::Star => BindingPower {
-            left_operand: 3.0,
-            right_operand: 3.1,
-        },
-        TokenType::Slash => BindingPower {
-            left_operand: 4.0,
-            right_operand: 4.1,
-        },
-        TokenType::Bang => BindingPower {
-            left_operand: 5.0,
-            right_operand: 5.1,
-        },
-        _ => panic!("unknown binding power for the operator: {}", operator),
+impl Precedence {
+    fn next(self) -> Self {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
     }
 }
 
-fn is_end(token: &Token) -> bool {
-    match token.token_type {
-        TokenType::Eof => true,
-        _ => false,
-    }
+/// compiles the expression starting with, or continuing at, the given token
+type ParseFn<'a> = fn(&mut Compiler<'a>, &Token, bool);
+
+struct ParseRule<'a> {
+    /// for tokens starting an expression
+    prefix: Option<ParseFn<'a>>,
+    /// for tokens following a complete operand
+    infix: Option<ParseFn<'a>>,
+    /// how tightly the infix operator binds
+    precedence: Precedence,
 }
 
-fn is_binary_operator(token: &Token) -> bool {
-    match token.token_type {
-        TokenType::Plus
-        | TokenType::Minus
-        | TokenType::Star
-        | TokenType::Slash
-        | TokenType::EqualEqual
-        | TokenType::Less
-        | TokenType::LessEqual
-        | TokenType::Greater
-        | TokenType::GreaterEqual
-        | TokenType::BangEqual
-        | TokenType::Or
-        | TokenType::And => true,
-        _ => false,
+fn get_rule<'a>(token_type: &TokenType) -> ParseRule<'a> {
+    let (prefix, infix, precedence): (Option<ParseFn<'a>>, Option<ParseFn<'a>>, _) =
+        match token_type {
+            TokenType::LeftParen => (
+                Some(Compiler::grouping),
+                Some(Compiler::call),
+                Precedence::Call,
+            ),
+            TokenType::Dot => (None, Some(Compiler::dot), Precedence::Call),
+            TokenType::Minus => (
+                Some(Compiler::unary),
+                Some(Compiler::binary),
+                Precedence::Term,
+            ),
+            TokenType::Plus => (None, Some(Compiler::binary), Precedence::Term),
+            TokenType::Bang => (Some(Compiler::unary), None, Precedence::None),
+            TokenType::Slash | TokenType::Star => {
+                (None, Some(Compiler::binary), Precedence::Factor)
+            }
+            TokenType::BangEqual | TokenType::EqualEqual => {
+                (None, Some(Compiler::binary), Precedence::Equality)
+            }
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => (None, Some(Compiler::binary), Precedence::Comparison),
+            TokenType::Identifier => (Some(Compiler::variable), None, Precedence::None),
+            TokenType::String => (Some(Compiler::string), None, Precedence::None),
+            TokenType::Number => (Some(Compiler::number), None, Precedence::None),
+            TokenType::True | TokenType::False | TokenType::Nil => {
+                (Some(Compiler::literal), None, Precedence::None)
+            }
+            TokenType::And => (None, Some(Compiler::and), Precedence::And),
+            TokenType::Or => (None, Some(Compiler::or), Precedence::Or),
+            TokenType::This => (Some(Compiler::this), None, Precedence::None),
+            TokenType::Super => (Some(Compiler::super_access), None, Precedence::None),
+            _ => (None, None, Precedence::None),
+        };
+    ParseRule {
+        prefix,
+        infix,
+        precedence,
     }
 }
 
+fn is_end(token: &Token) -> bool {
+    matches!(token.token_type, TokenType::Eof)
+}
+
 fn opcode_from_op(token: &Token) -> Opcode {
     match token.token_type {
         TokenType::Plus => Opcode::Add,
@@ -263,79 +1019,43 @@ fn opcode_from_op(token: &Token) -> Opcode {
         TokenType::GreaterEqual => Opcode::GreaterEqual,
         TokenType::Less => Opcode::Less,
         TokenType::LessEqual => Opcode::LessEqual,
-        TokenType::Bang => Opcode::Not,
         _ => panic!("can't convert the token: {} to opcode", token.lexeme),
     }
 }
 
-fn is_number(token: &Token) -> bool {
-    match token.token_type {
-        TokenType::Number => true,
-        _ => false,
-    }
-}
-
-fn is_string(token: &Token) -> bool {
-    match token.token_type {
-        TokenType::String => true,
-        _ => false,
-    }
-}
-
-fn is_op(token: &Token) -> bool {
-    match token.token_type {
-        TokenType::Plus => true,
-        _ => false,
-    }
-}
-
-fn emit_end(token: &Token) -> ByteCode {
-    let mut code = ByteCode::new();
-    code.write_code(Opcode::Ret as u8, token.line as u32);
-    code
+/// writes a jump with a placeholder offset and returns where the offset is
+fn emit_jump(code: &mut ByteCode, opcode: Opcode, line: u32) -> usize {
+    code.write_code(opcode as u8, line);
+    code.write_code(0xff, line);
+    code.write_code(0xff, line);
+    code.code.len() - 2
 }
 
-fn emit_number(token: &Token) -> ByteCode {
-    match token.token_type {
-        TokenType::Number => {
-            let num = token.lexeme.parse::<f64>().unwrap();
-            let mut code = ByteCode::new();
-            code.write_number(num);
-            code.write_code(Opcode::Num as u8, token.line as u32);
-            code.write_code(0, token.line as u32);
-            code.write_code(Opcode::Ret as u8, token.line as u32);
-            code
-        }
-        _ => panic!("can't emit number from a NaN"),
+/// returns false when the jump is too long for its 16 bit offset
+fn patch_jump(code: &mut ByteCode, offset: usize) -> bool {
+    let jump = code.code.len() - offset - 2;
+    if jump > u16::MAX as usize {
+        return false;
     }
+    let [high, low] = (jump as u16).to_be_bytes();
+    code.code[offset] = high;
+    code.code[offset + 1] = low;
+    true
 }
 
-fn emit_string(code: &mut ByteCode, token: &Token) {
-    match token.token_type {
-        TokenType::String => {
-            let str = token.lexeme.clone();
-            let str_index = code.strings.len();
-            code.write_string(str);
-            code.write_code(Opcode::Str as u8, token.line as u32);
-            code.write_code(str_index as u8, token.line as u32);
-        }
-        _ => panic!("can't emit string from a NaN"),
-    }
-}
-
-fn emit_op(code: &mut ByteCode, token: &Token) {
-    match token.token_type {
-        TokenType::Plus => {
-            code.write_code(Opcode::Add as u8, token.line as u32);
-        }
-        _ => panic!("can't emit string from a NaN"),
+/// token for the variables the compiler declares itself, such as `super`
+fn synthetic_token(token_type: TokenType, lexeme: &str, line: usize) -> Token {
+    Token {
+        token_type,
+        lexeme: lexeme.to_string(),
+        line,
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::scanner::token::{Token, TokenType};
-    use crate::vm::bytecode::ByteCode;
+    use crate::scanner::Scanner;
     use crate::vm::value::Value;
     use crate::vm::vm::{InterpretResult, VM};
 
@@ -402,10 +1122,10 @@ mod tests {
             },
         ];
 
-        let bytecode = compile(&tokens);
-        bytecode.disasm("2 - 6 / 2 + 2 * 4;");
+        let script = compile(&tokens).expect("compile error");
+        script.code.disasm("2 - 6 / 2 + 2 * 4;");
         let mut vm = VM::new();
-        let result = vm.interpret(&bytecode);
+        let result = vm.interpret(script);
         match result {
             InterpretResult::Ok(val) => {
                 assert_eq!(val, Value::Num(7.0));
@@ -534,10 +1254,10 @@ mod tests {
                 line: 1,
             },
         ];
-        let bytecode = compile(&tokens);
-        bytecode.disasm("( 2*  3 + (2 + 3)) * ((2 + 4) * 2);");
+        let script = compile(&tokens).expect("compile error");
+        script.code.disasm("( 2*  3 + (2 + 3)) * ((2 + 4) * 2);");
         let mut vm = VM::new();
-        let result = vm.interpret(&bytecode);
+        let result = vm.interpret(script);
         match result {
             InterpretResult::Ok(val) => {
                 assert_eq!(val, Value::Num(132.0));
@@ -545,4 +1265,501 @@ mod tests {
             _ => panic!("unexpected return"),
         }
     }
+
+    #[test]
+    fn associativity() {
+        let cases = [
+            ("10 - 4 - 3;", Value::Num(3.0)),
+            ("16 / 4 / 2;", Value::Num(2.0)),
+            ("1 - 2 + 3;", Value::Num(2.0)),
+            ("2 * 3 / 6;", Value::Num(1.0)),
+            ("2 + 3 * 4 - 10 / 5;", Value::Num(12.0)),
+            ("var a; var b; a = b = 3; a;", Value::Num(3.0)),
+        ];
+        for (source, expected) in cases {
+            assert_evaluates(source, expected);
+        }
+        for source in ["var a = 1; var b = 2; a * b = 3;", "1 + ;", "(1;"] {
+            assert!(
+                matches!(interpret_source(source), InterpretResult::CompileErr),
+                "{source}"
+            );
+        }
+    }
+
+    fn interpret_source(source: &str) -> InterpretResult {
+        let mut scanner = Scanner::new(source);
+        let script = match compile(scanner.scan_tokens()) {
+            Some(script) => script,
+            None => return InterpretResult::CompileErr,
+        };
+        script.code.disasm(source);
+        VM::new().interpret(script)
+    }
+
+    /// reports the source and what it resulted in when it doesn't evaluate to the expected value
+    #[track_caller]
+    fn assert_evaluates(source: &str, expected: Value) {
+        match interpret_source(source) {
+            InterpretResult::Ok(val) => assert_eq!(val, expected, "{source}"),
+            result => panic!("{source}\nexpected {expected:?}, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn global_variables() {
+        let source = "var a = 2; var b = a - 1; a = b = a * 10; a + b;";
+        assert_evaluates(source, Value::Num(40.0));
+        assert_evaluates("var a; a;", Value::Nil);
+        assert_evaluates("var a = 1;", Value::Nil);
+    }
+
+    #[test]
+    fn undefined_global_is_runtime_error() {
+        assert!(matches!(
+            interpret_source("var a = 1; a + b;"),
+            InterpretResult::RuntimeErr
+        ));
+        assert!(matches!(
+            interpret_source("b = 1;"),
+            InterpretResult::RuntimeErr
+        ));
+    }
+
+    #[test]
+    fn block_scopes() {
+        let source =
+            "var result; { var a = 1; { var b = a + 10; a = b * 2; } result = a; } result;";
+        assert_evaluates(source, Value::Num(22.0));
+        assert_evaluates(
+            "var a = 1; { var a = 2; { var a = 3; } } a;",
+            Value::Num(1.0),
+        );
+    }
+
+    #[test]
+    fn local_compile_errors() {
+        assert!(matches!(
+            interpret_source("{ var a = 1; var a = 2; }"),
+            InterpretResult::CompileErr
+        ));
+        assert!(matches!(
+            interpret_source("{ var a = a; }"),
+            InterpretResult::CompileErr
+        ));
+        let too_many_locals = (0..=256).map(|i| format!("var v{i};")).collect::<String>();
+        assert!(matches!(
+            interpret_source(&format!("{{ {too_many_locals} }}")),
+            InterpretResult::CompileErr
+        ));
+    }
+
+    #[test]
+    fn conditionals() {
+        let source = "var a = 1; var r; if (a < 2) r = 10; else r = 20; r;";
+        assert_evaluates(source, Value::Num(10.0));
+        let source = "var r = 0; if (1 > 2) { r = 10; } else if (2 > 2) r = 20; else { var b = 30; r = b; } r;";
+        assert_evaluates(source, Value::Num(30.0));
+        assert_evaluates("var r = 1; if (r > 2) r = 3; r;", Value::Num(1.0));
+    }
+
+    #[test]
+    fn logical_operators() {
+        let cases = [
+            ("1 and 2;", Value::Num(2.0)),
+            ("var n; n and 2;", Value::Nil),
+            ("var n; n or 3;", Value::Num(3.0)),
+            ("0 or 3;", Value::Num(0.0)),
+            ("1 < 2 and 3 < 2 or 4 == 4;", Value::Bool(true)),
+            ("1 < 2 or 3 < 2 and 4 == 5;", Value::Bool(true)),
+            (
+                "var x = 0; var n; n and (x = 1); 1 or (x = 2); x;",
+                Value::Num(0.0),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_evaluates(source, expected);
+        }
+    }
+
+    #[test]
+    fn loops() {
+        let cases = [
+            (
+                "var i = 0; var sum = 0; while (i < 5) { sum = sum + i; i = i + 1; } sum;",
+                Value::Num(10.0),
+            ),
+            (
+                "var sum = 0; for (var i = 1; i <= 4; i = i + 1) { var sq = i * i; sum = sum + sq; } sum;",
+                Value::Num(30.0),
+            ),
+            ("var i = 0; for (; i < 3;) i = i + 1; i;", Value::Num(3.0)),
+            (
+                "var n = 0; for (var i = 0; i < 3; i = i + 1) for (var j = 0; j < i; j = j + 1) n = n + 1; n;",
+                Value::Num(3.0),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_evaluates(source, expected);
+        }
+        // the loop variable is scoped to the loop
+        assert!(matches!(
+            interpret_source("for (var i = 0; i < 1; i = i + 1) {} i;"),
+            InterpretResult::RuntimeErr
+        ));
+    }
+
+    #[test]
+    fn functions() {
+        let cases = [
+            (
+                "fun add(a, b) { return a + b; } add(1, 2);",
+                Value::Num(3.0),
+            ),
+            (
+                "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); } fib(10);",
+                Value::Num(55.0),
+            ),
+            ("fun f() {} f();", Value::Nil),
+            ("fun f() { return; } f();", Value::Nil),
+            (
+                "var r; { fun twice(x) { var y = x * 2; return y; } r = twice(4); } r;",
+                Value::Num(8.0),
+            ),
+            (
+                "fun outer() { fun inner(a) { return a + 1; } return inner(1) + 1; } outer();",
+                Value::Num(3.0),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_evaluates(source, expected);
+        }
+    }
+
+    #[test]
+    fn function_errors() {
+        for source in [
+            "fun f(a) {} f();",
+            "var a = 1; a();",
+            "fun f() { f(); } f();",
+        ] {
+            assert!(
+                matches!(interpret_source(source), InterpretResult::RuntimeErr),
+                "{source}"
+            );
+        }
+        assert!(matches!(
+            interpret_source("return 1;"),
+            InterpretResult::CompileErr
+        ));
+    }
+
+    #[test]
+    fn closures() {
+        let cases = [
+            (
+                "fun make_adder(n) { fun adder(i) { return n + i; } return adder; } var add5 = make_adder(5); add5(10);",
+                Value::Num(15.0),
+            ),
+            (
+                "fun counter() { var c = 0; fun inc() { c = c + 1; return c; } return inc; } var f = counter(); f(); f(); f();",
+                Value::Num(3.0),
+            ),
+            // both closures share the captured variable
+            (
+                "var get; var set; fun pair() { var v = 1; fun g() { return v; } fun s(x) { v = x; } get = g; set = s; } pair(); set(5); get();",
+                Value::Num(5.0),
+            ),
+            (
+                "fun outer() { var x = 10; fun middle() { fun inner() { return x; } return inner; } return middle; } outer()()();",
+                Value::Num(10.0),
+            ),
+            // the variable is closed when its block ends, after the assignment
+            (
+                "var f; { var a = 1; fun g() { return a; } f = g; a = 2; } f();",
+                Value::Num(2.0),
+            ),
+            (
+                "var f; for (var i = 0; i < 3; i = i + 1) { var j = i; fun g() { return j; } if (i == 1) f = g; } f();",
+                Value::Num(1.0),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_evaluates(source, expected);
+        }
+    }
+
+    #[test]
+    fn make_adder_example() {
+        // as written in about_lox_language.md
+        let example = "fun make_adder(n) {
+  fun adder(i) {
+    return n + i;
+  }
+  return adder;
+}
+var add5 = make_adder(5);
+print add5(1);
+print add5(100);
+";
+        assert!(matches!(
+            interpret_source(example),
+            InterpretResult::Ok(Value::Nil)
+        ));
+        for (call, expected) in [("add5(1);", 6.0), ("add5(100);", 105.0)] {
+            assert_evaluates(&format!("{example}{call}"), Value::Num(expected));
+        }
+    }
+
+    #[test]
+    fn classes() {
+        let cases = [
+            (
+                "class Point { init(x, y) { this.x = x; this.y = y; } sum() { return this.x + this.y; } } var p = Point(1, 2); p.sum();",
+                Value::Num(3.0),
+            ),
+            (
+                "class Box {} var b = Box(); b.value = 4; b.value = b.value * 2; b.value;",
+                Value::Num(8.0),
+            ),
+            // bound methods remember their receiver
+            (
+                "class Counter { init() { this.n = 0; } inc() { this.n = this.n + 1; return this.n; } } var c = Counter(); var inc = c.inc; inc(); inc();",
+                Value::Num(2.0),
+            ),
+            // fields shadow methods
+            (
+                "fun ten() { return 10; } class A { m() { return 1; } } var a = A(); a.m = ten; a.m();",
+                Value::Num(10.0),
+            ),
+            (
+                "class A { init() { this.v = 1; return; } } var a = A(); a.init().v;",
+                Value::Num(1.0),
+            ),
+            (
+                "class A { m() { fun f() { return this.v; } return f; } } var a = A(); a.v = 7; a.m()();",
+                Value::Num(7.0),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_evaluates(source, expected);
+        }
+    }
+
+    #[test]
+    fn class_errors() {
+        for source in [
+            "class A {} A().missing;",
+            "class A {} A().missing();",
+            "class A {} A(1);",
+            "var a = 1; a.b = 2;",
+        ] {
+            assert!(
+                matches!(interpret_source(source), InterpretResult::RuntimeErr),
+                "{source}"
+            );
+        }
+        for source in ["this;", "class A { init() { return 1; } }"] {
+            assert!(
+                matches!(interpret_source(source), InterpretResult::CompileErr),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn inheritance() {
+        let cases = [
+            (
+                "class A { m() { return 1; } } class B < A {} B().m();",
+                Value::Num(1.0),
+            ),
+            (
+                "class A { m() { return 1; } } class B < A { m() { return super.m() + 10; } } B().m();",
+                Value::Num(11.0),
+            ),
+            (
+                "class A { init(v) { this.v = v; } } class B < A { init() { super.init(5); } } B().v;",
+                Value::Num(5.0),
+            ),
+            // super is resolved from the class declaring the method, not from the receiver
+            (
+                "class A { m() { return 1; } } class B < A { m() { return 2; } n() { var f = super.m; return f(); } } class C < B { m() { return 3; } } C().n();",
+                Value::Num(1.0),
+            ),
+            (
+                "var r; { class A { m() { return 4; } } class B < A { m() { fun f() { return super.m(); } return f; } } r = B().m()(); } r;",
+                Value::Num(4.0),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_evaluates(source, expected);
+        }
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        for source in [
+            "class A < A {}",
+            "super.m();",
+            "class A { m() { return super.m(); } }",
+        ] {
+            assert!(
+                matches!(interpret_source(source), InterpretResult::CompileErr),
+                "{source}"
+            );
+        }
+        for source in [
+            "var A = 1; class B < A {}",
+            "class A {} class B < A { m() { return super.missing(); } } B().m();",
+        ] {
+            assert!(
+                matches!(interpret_source(source), InterpretResult::RuntimeErr),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn print_statements() {
+        // printing consumes the value, so the program ends with no result
+        let source = "var a = 1; print a; { var b = 2; print a + b; } fun f() { print 3; } f();";
+        assert_evaluates(source, Value::Nil);
+        assert!(matches!(
+            interpret_source("print;"),
+            InterpretResult::CompileErr
+        ));
+    }
+
+    #[test]
+    fn native_functions() {
+        assert_evaluates("var start = clock(); clock() >= start;", Value::Bool(true));
+        assert!(matches!(
+            interpret_source("clock(1);"),
+            InterpretResult::RuntimeErr
+        ));
+
+        let mut vm = VM::new();
+        vm.register_native(
+            "max",
+            2,
+            Box::new(|arguments| match arguments {
+                [Value::Num(a), Value::Num(b)] => Ok(Value::Num(a.max(*b))),
+                _ => Err("max expects two numbers".to_string()),
+            }),
+        );
+        let mut scanner = Scanner::new("fun f(a) { return max(a, 3) + 1; } f(5);");
+        let script = compile(scanner.scan_tokens()).expect("compile error");
+        match vm.interpret(script) {
+            InterpretResult::Ok(val) => assert_eq!(val, Value::Num(6.0)),
+            _ => panic!("unexpected return"),
+        }
+        let mut scanner = Scanner::new("var m = max; m(1, nil);");
+        let script = compile(scanner.scan_tokens()).expect("compile error");
+        assert!(matches!(vm.interpret(script), InterpretResult::RuntimeErr));
+    }
+
+    #[test]
+    fn unary_operators_and_literals() {
+        let cases = [
+            ("-3;", Value::Num(-3.0)),
+            ("4 - -2;", Value::Num(6.0)),
+            ("--2;", Value::Num(2.0)),
+            ("-(1 + 2) * 2;", Value::Num(-6.0)),
+            ("var a = 5; -a - 1;", Value::Num(-6.0)),
+            ("!true;", Value::Bool(false)),
+            ("!nil;", Value::Bool(true)),
+            ("!!0;", Value::Bool(true)),
+            ("!(1 < 2) == false;", Value::Bool(true)),
+            ("nil;", Value::Nil),
+            ("nil == nil;", Value::Bool(true)),
+            ("nil == false;", Value::Bool(false)),
+            ("1 != \"1\";", Value::Bool(true)),
+            (
+                "var s = \"b\"; \"a\" + s + \"c\" == \"abc\";",
+                Value::Bool(true),
+            ),
+            (
+                "fun f(x) { return x; } f(\"x\") == \"x\";",
+                Value::Bool(true),
+            ),
+        ];
+        for (source, expected) in cases {
+            assert_evaluates(source, expected);
+        }
+        assert!(matches!(
+            interpret_source("-nil;"),
+            InterpretResult::RuntimeErr
+        ));
+    }
+
+    #[test]
+    fn operand_type_errors() {
+        let sources = [
+            "print 1 + nil;",
+            "print \"a\" + 1;",
+            "print true - 1;",
+            "print 1 * \"a\";",
+            "print nil / 2;",
+            "print \"a\" < 1;",
+            "print \"a\" <= \"b\";",
+            "print 1 > false;",
+            "print nil >= 1;",
+        ];
+        for source in sources {
+            assert!(
+                matches!(interpret_source(source), InterpretResult::RuntimeErr),
+                "{source}"
+            );
+        }
+    }
+
+    #[test]
+    fn long_constants() {
+        // every declaration adds a number and a name, well past the 256 of a one byte operand
+        let mut source = (0..1000)
+            .map(|i| format!("var v{i} = {i};"))
+            .collect::<String>();
+        source.push_str("var s = \"a\"; v999 = v999 + v1; s = s + \"b\"; v999 + v3;");
+        assert_evaluates(&source, Value::Num(1003.0));
+        // functions, classes, methods and properties declared after the first 256 constants
+        let globals = (0..300)
+            .map(|i| format!("var v{i} = {i};"))
+            .collect::<String>();
+        let literals = (0..300).map(|i| format!("{i};")).collect::<String>();
+        let source = format!(
+            "{globals}
+            fun f(n) {{ return n + 1; }}
+            class A {{ init(x) {{ this.x = x; }} get() {{ return this.x; }} }}
+            class B < A {{
+                get() {{ {literals} return super.get() + 1; }}
+                bound() {{ {literals} var m = super.get; return m(); }}
+            }}
+            var b = B(v1);
+            b.y = 2;
+            var m = b.get;
+            f(b.get()) + b.y + m() + b.bound();"
+        );
+        assert_evaluates(&source, Value::Num(8.0));
+    }
+
+    #[test]
+    fn garbage_collection() {
+        // objects in use must survive the collections, the gc_stress feature collects the most often
+        let source = "
+            class Node { init(next) { this.next = next; } }
+            fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; }
+            var list = nil;
+            var count = counter();
+            for (var i = 0; i < 100; i = i + 1) {
+                var cycle = Node(nil);
+                cycle.next = cycle;
+                list = Node(list);
+                if (\"a\" + \"b\" == \"ab\") count();
+            }
+            var length = 0;
+            while (list != nil) { length = length + 1; list = list.next; }
+            length + count();";
+        assert_evaluates(source, Value::Num(201.0));
+    }
 }
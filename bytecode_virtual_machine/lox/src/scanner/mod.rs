@@ -1,4 +1,3 @@
-#![allow(dead_code)]
 use crate::scanner::token::{Token, TokenType};
 use std::collections::HashMap;
 use std::process;
@@ -12,7 +11,7 @@ pub struct Scanner {
     cursor: usize,
 }
 impl Scanner {
-    pub fn new(source: &String) -> Scanner {
+    pub fn new(source: &str) -> Scanner {
         Scanner {
             source: source.chars().collect(),
             tokens: vec![],
@@ -38,36 +37,13 @@ impl Scanner {
         if self.cursor >= self.source.len() {
             None
         } else {
-            let retval = self.source[self.cursor].clone();
+            let retval = self.source[self.cursor];
             self.cursor += 1;
             Some(retval)
         }
     }
-    fn rewind(&mut self) {
-        self.cursor -= 1;
-    }
-    fn next_non_whitespace(&self) -> Option<&char> {
-        let mut cursor = self.cursor;
-        loop {
-            if cursor >= self.source.len() {
-                return None;
-            }
-            cursor += 1;
-            if !Self::is_whitespace(&self.source[cursor]) {
-                return Some(&self.source[cursor]);
-            }
-        }
-    }
-    fn eat_whitespace(&mut self) {
-        while self.peek() != None {
-            if !Self::is_whitespace(self.peek().unwrap()) {
-                break;
-            }
-            self.next();
-        }
-    }
     pub fn scan_tokens(&mut self) -> &Vec<Token> {
-        while self.peek() != None {
+        while self.peek().is_some() {
             self.scan_token();
         }
         self.add_token(TokenType::Eof, String::new());
@@ -90,14 +66,7 @@ impl Scanner {
                 ';' => self.add_token(TokenType::Semicolon, String::from(";")),
 
                 '+' => self.add_token(TokenType::Plus, String::from("+")),
-                '-' => {
-                    if self.is_signed_number() {
-                        self.eat_whitespace();
-                        self.match_number('-');
-                    } else {
-                        self.add_token(TokenType::Minus, String::from("-"));
-                    }
-                }
+                '-' => self.add_token(TokenType::Minus, String::from("-")),
 
                 '*' => self.add_token(TokenType::Star, String::from("*")),
                 '!' => {
@@ -176,7 +145,7 @@ impl Scanner {
                     lexeme.push('\n');
                 }
                 Some(c) => {
-                    lexeme.push(c.clone());
+                    lexeme.push(c);
                 }
                 None => {
                     eprintln!("Line {} has Unterminated string", self.line);
@@ -191,12 +160,12 @@ impl Scanner {
         let mut decimal_read = false;
         loop {
             match self.peek() {
-                Some(c) if Self::is_digit(c.clone()) => {
-                    lexeme.push(c.clone());
+                Some(&c) if Self::is_digit(c) => {
+                    lexeme.push(c);
                     self.next();
                 }
                 Some('.') if !decimal_read => match self.peek_further() {
-                    Some(c) if Self::is_digit(c.clone()) => {
+                    Some(&c) if Self::is_digit(c) => {
                         lexeme.push('.');
                         decimal_read = true;
                         self.next();
@@ -215,8 +184,8 @@ impl Scanner {
         let mut lexeme = String::from(starting_char);
         loop {
             match self.peek() {
-                Some(c) if Self::is_alphabetic(c.clone()) || Self::is_digit(c.clone()) => {
-                    lexeme.push(c.clone());
+                Some(&c) if Self::is_alphabetic(c) || Self::is_digit(c) => {
+                    lexeme.push(c);
                     self.next();
                 }
                 _ => {
@@ -261,7 +230,7 @@ impl Scanner {
         unmatch_lexeme: String,
     ) {
         match self.peek() {
-            Some(c) if c.clone() == to_match => {
+            Some(&c) if c == to_match => {
                 self.next();
                 self.add_token(match_token, match_lexeme);
             }
@@ -275,47 +244,12 @@ impl Scanner {
         self.tokens.push(Token::new(token_type, lexeme, self.line));
     }
 
-    fn is_signed_number(&mut self) -> bool {
-        self.rewind();
-        let next = self.next_non_whitespace();
-        if next == None {
-            self.next();
-            return false;
-        }
-        let next_is_digit = Self::is_digit(next.unwrap().clone());
-        if !next_is_digit {
-            self.next();
-            return false;
-        }
-        let prev_token = self.tokens.last();
-        let retval = match prev_token {
-            Some(token) => match token.token_type {
-                TokenType::Number => false,
-                _ => true,
-            },
-            _ => true,
-        };
-
-        self.next();
-        retval
-    }
-
     fn is_digit(ch: char) -> bool {
-        match ch {
-            '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => true,
-            _ => false,
-        }
+        ch.is_ascii_digit()
     }
 
     fn is_alphabetic(ch: char) -> bool {
-        match ch {
-            'a'..='z' | 'A'..='Z' => true,
-            _ => false,
-        }
-    }
-
-    fn is_whitespace(ch: &char) -> bool {
-        *ch == ' ' || *ch == '\r' || *ch == '\n'
+        ch.is_ascii_alphabetic() || ch == '_'
     }
 }
 
@@ -356,21 +290,35 @@ mod tests {
     }
 
     #[test]
-    fn signed_number() {
-        let source = "4 - - 2.3 > - 2".to_string();
+    fn minus_is_never_part_of_a_number() {
+        // negation is a unary operator applied by the compiler
+        let source = "4 - -2.3 > - 2".to_string();
         let mut scanner = Scanner::new(&source);
         let tokens = scanner.scan_tokens();
-        assert_eq!(tokens.len(), 6);
+        assert_eq!(tokens.len(), 8);
         assert_eq!(tokens[0].token_type, TokenType::Number);
         assert_eq!(tokens[0].lexeme, "4".to_string());
         assert_eq!(tokens[1].token_type, TokenType::Minus);
-        assert_eq!(tokens[1].lexeme, "-".to_string());
-        assert_eq!(tokens[2].token_type, TokenType::Number);
-        assert_eq!(tokens[2].lexeme, "-2.3".to_string());
-        assert_eq!(tokens[3].token_type, TokenType::Greater);
-        assert_eq!(tokens[3].lexeme, ">".to_string());
-        assert_eq!(tokens[4].token_type, TokenType::Number);
-        assert_eq!(tokens[4].lexeme, "-2".to_string());
-        assert_eq!(tokens[5].token_type, TokenType::Eof);
+        assert_eq!(tokens[2].token_type, TokenType::Minus);
+        assert_eq!(tokens[2].lexeme, "-".to_string());
+        assert_eq!(tokens[3].token_type, TokenType::Number);
+        assert_eq!(tokens[3].lexeme, "2.3".to_string());
+        assert_eq!(tokens[4].token_type, TokenType::Greater);
+        assert_eq!(tokens[5].token_type, TokenType::Minus);
+        assert_eq!(tokens[6].token_type, TokenType::Number);
+        assert_eq!(tokens[6].lexeme, "2".to_string());
+        assert_eq!(tokens[7].token_type, TokenType::Eof);
+    }
+
+    #[test]
+    fn minus_after_operand() {
+        let source = "a -2 == (b) - 1".to_string();
+        let mut scanner = Scanner::new(&source);
+        let tokens = scanner.scan_tokens();
+        assert_eq!(tokens.len(), 10);
+        assert_eq!(tokens[1].token_type, TokenType::Minus);
+        assert_eq!(tokens[2].lexeme, "2".to_string());
+        assert_eq!(tokens[7].token_type, TokenType::Minus);
+        assert_eq!(tokens[8].lexeme, "1".to_string());
     }
 }
@@ -1,12 +1,13 @@
-#![allow(dead_code)]
+use std::collections::HashMap;
+use std::rc::Rc;
 
-use std::{string, usize};
+use super::value::Value;
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
     Ret = 0,
-    Num = 1,
+    Constant = 1,
     Neg = 2,
     Add = 3,
     Sub = 4,
@@ -22,14 +23,94 @@ pub enum Opcode {
     LessEqual = 14,
     NotEqual = 15,
 
-    Str = 16,
+    Nil = 17,
+    Pop = 18,
+
+    DefineGlobal = 19,
+    GetGlobal = 20,
+    SetGlobal = 21,
+    GetLocal = 22,
+    SetLocal = 23,
+
+    Jump = 24,
+    JumpIfFalse = 25,
+    Loop = 26,
+
+    Closure = 27,
+    Call = 28,
+
+    GetUpvalue = 29,
+    SetUpvalue = 30,
+    CloseUpvalue = 31,
+
+    Class = 32,
+    Method = 33,
+    GetProperty = 34,
+    SetProperty = 35,
+    Invoke = 36,
+    Inherit = 37,
+    GetSuper = 38,
+    SuperInvoke = 39,
+
+    Print = 40,
+
+    // same as their short forms with a 24 bit constant index
+    ConstantLong = 41,
+    DefineGlobalLong = 43,
+    GetGlobalLong = 44,
+    SetGlobalLong = 45,
+    ClosureLong = 46,
+    ClassLong = 47,
+    MethodLong = 48,
+    GetPropertyLong = 49,
+    SetPropertyLong = 50,
+    InvokeLong = 51,
+    GetSuperLong = 52,
+    SuperInvokeLong = 53,
+}
+impl Opcode {
+    /// variant taking a 24 bit constant index, for instructions that have one
+    pub fn long_form(self) -> Option<Opcode> {
+        match self {
+            Opcode::Constant => Some(Opcode::ConstantLong),
+            Opcode::DefineGlobal => Some(Opcode::DefineGlobalLong),
+            Opcode::GetGlobal => Some(Opcode::GetGlobalLong),
+            Opcode::SetGlobal => Some(Opcode::SetGlobalLong),
+            Opcode::Closure => Some(Opcode::ClosureLong),
+            Opcode::Class => Some(Opcode::ClassLong),
+            Opcode::Method => Some(Opcode::MethodLong),
+            Opcode::GetProperty => Some(Opcode::GetPropertyLong),
+            Opcode::SetProperty => Some(Opcode::SetPropertyLong),
+            Opcode::Invoke => Some(Opcode::InvokeLong),
+            Opcode::GetSuper => Some(Opcode::GetSuperLong),
+            Opcode::SuperInvoke => Some(Opcode::SuperInvokeLong),
+            _ => None,
+        }
+    }
+    fn is_long(self) -> bool {
+        matches!(
+            self,
+            Opcode::ConstantLong
+                | Opcode::DefineGlobalLong
+                | Opcode::GetGlobalLong
+                | Opcode::SetGlobalLong
+                | Opcode::ClosureLong
+                | Opcode::ClassLong
+                | Opcode::MethodLong
+                | Opcode::GetPropertyLong
+                | Opcode::SetPropertyLong
+                | Opcode::InvokeLong
+                | Opcode::GetSuperLong
+                | Opcode::SuperInvokeLong
+        )
+    }
 }
 impl TryFrom<u8> for Opcode {
     type Error = ();
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(Opcode::Ret),
-            1 => Ok(Opcode::Num),
+            1 => Ok(Opcode::Constant),
             2 => Ok(Opcode::Neg),
             3 => Ok(Opcode::Add),
             4 => Ok(Opcode::Sub),
@@ -44,79 +125,91 @@ impl TryFrom<u8> for Opcode {
             13 => Ok(Opcode::GreaterEqual),
             14 => Ok(Opcode::LessEqual),
             15 => Ok(Opcode::NotEqual),
-            16 => Ok(Opcode::Str),
+            17 => Ok(Opcode::Nil),
+            18 => Ok(Opcode::Pop),
+            19 => Ok(Opcode::DefineGlobal),
+            20 => Ok(Opcode::GetGlobal),
+            21 => Ok(Opcode::SetGlobal),
+            22 => Ok(Opcode::GetLocal),
+            23 => Ok(Opcode::SetLocal),
+            24 => Ok(Opcode::Jump),
+            25 => Ok(Opcode::JumpIfFalse),
+            26 => Ok(Opcode::Loop),
+            27 => Ok(Opcode::Closure),
+            28 => Ok(Opcode::Call),
+            29 => Ok(Opcode::GetUpvalue),
+            30 => Ok(Opcode::SetUpvalue),
+            31 => Ok(Opcode::CloseUpvalue),
+            32 => Ok(Opcode::Class),
+            33 => Ok(Opcode::Method),
+            34 => Ok(Opcode::GetProperty),
+            35 => Ok(Opcode::SetProperty),
+            36 => Ok(Opcode::Invoke),
+            37 => Ok(Opcode::Inherit),
+            38 => Ok(Opcode::GetSuper),
+            39 => Ok(Opcode::SuperInvoke),
+            40 => Ok(Opcode::Print),
+            41 => Ok(Opcode::ConstantLong),
+            43 => Ok(Opcode::DefineGlobalLong),
+            44 => Ok(Opcode::GetGlobalLong),
+            45 => Ok(Opcode::SetGlobalLong),
+            46 => Ok(Opcode::ClosureLong),
+            47 => Ok(Opcode::ClassLong),
+            48 => Ok(Opcode::MethodLong),
+            49 => Ok(Opcode::GetPropertyLong),
+            50 => Ok(Opcode::SetPropertyLong),
+            51 => Ok(Opcode::InvokeLong),
+            52 => Ok(Opcode::GetSuperLong),
+            53 => Ok(Opcode::SuperInvokeLong),
             _ => Err(()),
         }
     }
 }
 
+/// numbers and strings equal to an existing constant reuse its index
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    /// bit pattern, so that 0 and -0 stay distinct constants
+    Num(u64),
+    Str(Rc<str>),
+}
+
 #[derive(Debug)]
 pub struct ByteCode {
     pub code: Vec<u8>,
-    pub numbers: Vec<f64>,
-    pub strings: Vec<String>,
+    /// literals, variable and property names, and the functions declared in the chunk
+    pub constants: Vec<Value>,
+    constant_indexes: HashMap<ConstantKey, usize>,
     pub line_info: Vec<u32>,
 }
 impl ByteCode {
     pub fn new() -> Self {
         ByteCode {
             code: Vec::new(),
-            numbers: Vec::new(),
-            strings: Vec::new(),
+            constants: Vec::new(),
+            constant_indexes: HashMap::new(),
             line_info: Vec::new(),
         }
     }
-    pub fn is_nop(&self) -> bool {
-        self.code.len() == 0
-    }
-    pub fn merge_binary(left: &ByteCode, right: &ByteCode, operation: Opcode, line: u32) -> Self {
-        let mut code = ByteCode::new();
-        let num_offset = left.numbers.len();
-        let str_offset = left.strings.len();
-        ByteCode::steal_data(&mut code, left);
-        ByteCode::steal_data(&mut code, right);
-        ByteCode::steal_code(&mut code, left, 0, 0);
-        ByteCode::steal_code(&mut code, right, num_offset as u8, str_offset as u8);
-        code.write_code(operation as u8, line);
-        code.write_code(Opcode::Ret as u8, line);
-        code
-    }
-    fn steal_data(target: &mut ByteCode, source: &ByteCode) {
-        for n in &source.numbers {
-            target.write_number(n.clone());
-        }
-        for s in &source.strings {
-            target.write_string(s.clone());
-        }
-    }
-    fn steal_code(target: &mut ByteCode, source: &ByteCode, num_offset: u8, str_offset: u8) {
-        let mut cursor = 0;
-        loop {
-            let opcode = Opcode::try_from(source.code[cursor]).unwrap();
-            match opcode {
-                Opcode::Ret => return,
-                Opcode::Num => {
-                    target.write_code(Opcode::Num as u8, source.line_info[cursor]);
-                    cursor += 1;
-                    let addr = source.code[cursor];
-                    target.write_code(addr + num_offset, source.line_info[cursor]);
-                }
-                _ => {
-                    target.write_code(source.code[cursor], source.line_info[cursor]);
-                }
-            }
-            cursor += 1;
-        }
-    }
     pub fn write_code(&mut self, byte: u8, line: u32) {
         self.code.push(byte);
         self.line_info.push(line);
     }
-    pub fn write_number(&mut self, byte: f64) {
-        self.numbers.push(byte);
-    }
-    pub fn write_string(&mut self, str: String) {
-        self.strings.push(str);
+    /// returns the index of the constant, which is shared with an equal number or string
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = match &value {
+            Value::Num(n) => Some(ConstantKey::Num(n.to_bits())),
+            Value::Str(s) => Some(ConstantKey::Str(s.clone())),
+            _ => None,
+        };
+        if let Some(key) = key {
+            if let Some(index) = self.constant_indexes.get(&key) {
+                return *index;
+            }
+            self.constant_indexes.insert(key, self.constants.len());
+        }
+        self.constants.push(value);
+        self.constants.len() - 1
     }
     pub fn fetch_instruction(&self, ip: &mut usize) -> Opcode {
         if *ip >= self.code.len() {
@@ -134,18 +227,39 @@ impl ByteCode {
         *ip += 1;
         retval
     }
-    pub fn fetch_number(&self, addr: usize) -> f64 {
-        if addr >= self.numbers.len() {
-            panic!("attempted to fetch data outside the data section boundary");
+    pub fn fetch_short(&self, ip: &mut usize) -> u16 {
+        let high = self.fetch_operand(ip);
+        let low = self.fetch_operand(ip);
+        u16::from_be_bytes([high, low])
+    }
+    /// index of the constant used by `opcode`, one byte wide or three for the long forms
+    pub fn fetch_constant_index(&self, opcode: Opcode, ip: &mut usize) -> usize {
+        if !opcode.is_long() {
+            return self.fetch_operand(ip) as usize;
         }
-        self.numbers[addr]
+        let high = self.fetch_operand(ip);
+        let middle = self.fetch_operand(ip);
+        let low = self.fetch_operand(ip);
+        u32::from_be_bytes([0, high, middle, low]) as usize
     }
-    pub fn fetch_string(&self, addr: usize) -> &String {
-        if addr >= self.strings.len() {
+    pub fn fetch_constant(&self, addr: usize) -> &Value {
+        if addr >= self.constants.len() {
             panic!("attempted to fetch data outside the data section boundary");
         }
-        &self.strings[addr]
+        &self.constants[addr]
+    }
+    /// names of variables, properties and classes are string constants
+    pub fn fetch_name(&self, addr: usize) -> &Rc<str> {
+        match self.fetch_constant(addr) {
+            Value::Str(name) => name,
+            _ => panic!("the constant at {addr} is not a name"),
+        }
     }
+}
+
+/// Disassembler, only needed to trace the execution
+#[cfg(any(test, feature = "debug_exec_trace"))]
+impl ByteCode {
     pub fn disasm(&self, name: &str) {
         println!("====== Code section ({name}) ======");
         let mut offset = 0;
@@ -153,28 +267,31 @@ impl ByteCode {
             offset = self.disasm_instruction(offset);
         }
         self.disasm_data(name);
+        for constant in &self.constants {
+            if let Value::Function(function) = constant {
+                function.code.disasm(&function.name);
+            }
+        }
     }
     fn disasm_data(&self, name: &str) {
         println!("====== data section ({name}) ======");
-        println!("Numbers: [{}]", {
-            self.numbers
+        println!("Constants: [{}]", {
+            self.constants
                 .iter()
                 .map(|v| v.to_string())
                 .collect::<Vec<String>>()
                 .join(", ")
         });
-        println!("Strings: [{}]", { self.strings.join(", ") });
     }
     pub fn disasm_instruction(&self, offset: usize) -> usize {
         print!("{:#06x} ", offset);
         let opcode = Opcode::try_from(self.code[offset]);
-        if let Err(_) = opcode {
+        if opcode.is_err() {
             panic!("No opcode with a byte: {}", self.code[offset]);
         };
         match opcode.unwrap() {
             Opcode::Ret => self.simple_instruction("Ret", offset),
-            Opcode::Num => self.num_instruction("Num", offset),
-            Opcode::Str => self.str_instruction("Str", offset),
+            Opcode::Constant => self.constant_instruction("Constant", offset),
             Opcode::Neg => self.simple_instruction("Neg", offset),
             Opcode::Add => self.simple_instruction("Add", offset),
             Opcode::Sub => self.simple_instruction("Sub", offset),
@@ -189,29 +306,113 @@ impl ByteCode {
             Opcode::GreaterEqual => self.simple_instruction(">=", offset),
             Opcode::Less => self.simple_instruction("<", offset),
             Opcode::LessEqual => self.simple_instruction("<=", offset),
+            Opcode::Nil => self.simple_instruction("Nil", offset),
+            Opcode::Pop => self.simple_instruction("Pop", offset),
+            Opcode::DefineGlobal => self.constant_instruction("DefineGlobal", offset),
+            Opcode::GetGlobal => self.constant_instruction("GetGlobal", offset),
+            Opcode::SetGlobal => self.constant_instruction("SetGlobal", offset),
+            Opcode::GetLocal => self.byte_instruction("GetLocal", offset),
+            Opcode::SetLocal => self.byte_instruction("SetLocal", offset),
+            Opcode::Jump => self.jump_instruction("Jump", offset),
+            Opcode::JumpIfFalse => self.jump_instruction("JumpIfFalse", offset),
+            Opcode::Loop => self.jump_instruction("Loop", offset),
+            Opcode::Closure => self.closure_instruction("Closure", offset),
+            Opcode::Call => self.byte_instruction("Call", offset),
+            Opcode::GetUpvalue => self.byte_instruction("GetUpvalue", offset),
+            Opcode::SetUpvalue => self.byte_instruction("SetUpvalue", offset),
+            Opcode::CloseUpvalue => self.simple_instruction("CloseUpvalue", offset),
+            Opcode::Class => self.constant_instruction("Class", offset),
+            Opcode::Method => self.constant_instruction("Method", offset),
+            Opcode::GetProperty => self.constant_instruction("GetProperty", offset),
+            Opcode::SetProperty => self.constant_instruction("SetProperty", offset),
+            Opcode::Invoke => self.invoke_instruction("Invoke", offset),
+            Opcode::Inherit => self.simple_instruction("Inherit", offset),
+            Opcode::GetSuper => self.constant_instruction("GetSuper", offset),
+            Opcode::SuperInvoke => self.invoke_instruction("SuperInvoke", offset),
+            Opcode::Print => self.simple_instruction("Print", offset),
+            Opcode::ConstantLong => self.constant_instruction("ConstantLong", offset),
+            Opcode::DefineGlobalLong => self.constant_instruction("DefineGlobalLong", offset),
+            Opcode::GetGlobalLong => self.constant_instruction("GetGlobalLong", offset),
+            Opcode::SetGlobalLong => self.constant_instruction("SetGlobalLong", offset),
+            Opcode::ClosureLong => self.closure_instruction("ClosureLong", offset),
+            Opcode::ClassLong => self.constant_instruction("ClassLong", offset),
+            Opcode::MethodLong => self.constant_instruction("MethodLong", offset),
+            Opcode::GetPropertyLong => self.constant_instruction("GetPropertyLong", offset),
+            Opcode::SetPropertyLong => self.constant_instruction("SetPropertyLong", offset),
+            Opcode::InvokeLong => self.invoke_instruction("InvokeLong", offset),
+            Opcode::GetSuperLong => self.constant_instruction("GetSuperLong", offset),
+            Opcode::SuperInvokeLong => self.invoke_instruction("SuperInvokeLong", offset),
         }
     }
     fn simple_instruction(&self, name: &str, offset: usize) -> usize {
         println!("{name}");
         offset + 1
     }
-    fn num_instruction(&self, name: &str, offset: usize) -> usize {
-        let data_offset = self.code[offset + 1] as usize;
-        if data_offset >= self.numbers.len() {
-            panic!("attemptinng to read outside of data section");
-        }
-        let value = self.numbers[data_offset];
-        println!("{} {:#06x} '{}'", name, data_offset, value);
+    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+        let slot = self.code[offset + 1];
+        println!("{} {:#06x}", name, slot);
         offset + 2
     }
-    fn str_instruction(&self, name: &str, offset: usize) -> usize {
-        let data_offset = self.code[offset + 1] as usize;
-        if data_offset >= self.strings.len() {
-            panic!("attemptinng to read outside of data section");
+    fn jump_instruction(&self, name: &str, offset: usize) -> usize {
+        let operand = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        println!(
+            "{} {:#06x} -> {:#06x}",
+            name,
+            operand,
+            self.jump_target(offset)
+        );
+        offset + 3
+    }
+    /// offset of the instruction the jump at `offset` lands on
+    pub fn jump_target(&self, offset: usize) -> usize {
+        let operand = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]) as usize;
+        match Opcode::try_from(self.code[offset]) {
+            Ok(Opcode::Loop) => offset + 3 - operand,
+            _ => offset + 3 + operand,
+        }
+    }
+    fn opcode_at(&self, offset: usize) -> Opcode {
+        Opcode::try_from(self.code[offset]).expect("Not a valid opcode at the given offset")
+    }
+    fn closure_instruction(&self, name: &str, offset: usize) -> usize {
+        let mut next = offset + 1;
+        let data_offset = self.fetch_constant_index(self.opcode_at(offset), &mut next);
+        let function = match self.fetch_constant(data_offset) {
+            Value::Function(function) => function,
+            _ => panic!("the constant at {data_offset} is not a function"),
+        };
+        println!("{} {:#06x} '{}'", name, data_offset, function.name);
+        let mut offset = next;
+        for _ in 0..function.upvalue_count {
+            let kind = if self.code[offset] == 1 {
+                "local"
+            } else {
+                "upvalue"
+            };
+            println!(
+                "{:#06x}   | {} {:#06x}",
+                offset,
+                kind,
+                self.code[offset + 1]
+            );
+            offset += 2;
         }
-        let value = &self.strings[data_offset];
+        offset
+    }
+    fn invoke_instruction(&self, name: &str, offset: usize) -> usize {
+        let mut next = offset + 1;
+        let data_offset = self.fetch_constant_index(self.opcode_at(offset), &mut next);
+        let value = self.fetch_constant(data_offset);
+        let arg_count = self.code[next];
+        println!("{name} ({arg_count} args) {data_offset:#06x} '{value}'");
+        next + 1
+    }
+    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+        let mut next = offset + 1;
+        let data_offset = self.fetch_constant_index(self.opcode_at(offset), &mut next);
+        let value = self.fetch_constant(data_offset);
         println!("{} {:#06x} '{}'", name, data_offset, value);
-        offset + 2
+        next
     }
 }
 
@@ -220,50 +421,63 @@ mod tests {
     use super::*;
 
     #[test]
-    fn merge_works() {
-        // left = 2 + 3
-        // right = 6 / 2
-        // total = (2 + 3) - (6 / 2)
-        let mut left = ByteCode::new();
-        left.write_number(2.0);
-        left.write_number(3.0);
-        left.write_code(Opcode::Num as u8, 1);
-        left.write_code(0, 1);
-        left.write_code(Opcode::Num as u8, 1);
-        left.write_code(1, 1);
-        left.write_code(Opcode::Add as u8, 1);
-        left.write_code(Opcode::Ret as u8, 1);
-
-        let mut right = ByteCode::new();
-        right.write_number(6.0);
-        right.write_number(2.0);
-        right.write_code(Opcode::Num as u8, 2);
-        right.write_code(0, 2);
-        right.write_code(Opcode::Num as u8, 2);
-        right.write_code(1, 2);
-        right.write_code(Opcode::Div as u8, 2);
-        right.write_code(Opcode::Ret as u8, 2);
-
-        let merged = ByteCode::merge_binary(&left, &right, Opcode::Sub, 2);
+    fn jump_targets() {
+        let mut code = ByteCode::new();
+        code.write_code(Opcode::Jump as u8, 1);
+        code.write_code(0, 1);
+        code.write_code(2, 1);
+        code.write_code(Opcode::Nil as u8, 1);
+        code.write_code(Opcode::Pop as u8, 1);
+        code.write_code(Opcode::Loop as u8, 1);
+        code.write_code(0, 1);
+        code.write_code(8, 1);
+        code.disasm("jumps");
+        assert_eq!(code.jump_target(0), 5);
+        assert_eq!(code.jump_target(5), 0);
+    }
 
-        let mut expected = ByteCode::new();
-        expected.write_number(2.0);
-        expected.write_number(3.0);
-        expected.write_code(Opcode::Num as u8, 1);
-        expected.write_code(0, 1);
-        expected.write_code(Opcode::Num as u8, 1);
-        expected.write_code(1, 1);
-        expected.write_code(Opcode::Add as u8, 1);
-        expected.write_number(6.0);
-        expected.write_number(2.0);
-        expected.write_code(Opcode::Num as u8, 2);
-        expected.write_code(2, 2);
-        expected.write_code(Opcode::Num as u8, 2);
-        expected.write_code(3, 2);
-        expected.write_code(Opcode::Div as u8, 2);
-        expected.write_code(Opcode::Sub as u8, 2);
-        expected.write_code(Opcode::Ret as u8, 2);
+    #[test]
+    fn long_constant_operands() {
+        let mut code = ByteCode::new();
+        for i in 0..70000 {
+            code.add_constant(Value::Num(i as f64));
+        }
+        code.write_code(Opcode::ConstantLong as u8, 1);
+        code.write_code(0x01, 1);
+        code.write_code(0x11, 1);
+        code.write_code(0x6c, 1);
+        code.write_code(Opcode::Constant as u8, 1);
+        code.write_code(0x02, 1);
+        code.disasm_instruction(0);
+        let mut ip = 1;
+        assert_eq!(
+            code.fetch_constant_index(Opcode::ConstantLong, &mut ip),
+            70000 - 4
+        );
+        assert_eq!(ip, 4);
+        ip += 1;
+        assert_eq!(code.fetch_constant_index(Opcode::Constant, &mut ip), 2);
+        assert_eq!(ip, 6);
+        assert_eq!(Opcode::Constant.long_form(), Some(Opcode::ConstantLong));
+        assert_eq!(
+            Opcode::GetProperty.long_form(),
+            Some(Opcode::GetPropertyLong)
+        );
+        assert_eq!(Opcode::Call.long_form(), None);
+    }
 
-        assert_eq!(merged.disasm(""), expected.disasm(""));
+    #[test]
+    fn constants_are_deduplicated() {
+        let mut code = ByteCode::new();
+        assert_eq!(code.add_constant(Value::Num(1.0)), 0);
+        assert_eq!(code.add_constant(Value::Str(Rc::from("a"))), 1);
+        assert_eq!(code.add_constant(Value::Num(1.0)), 0);
+        assert_eq!(code.add_constant(Value::Str(Rc::from("a"))), 1);
+        // strings and numbers never share an entry
+        assert_eq!(code.add_constant(Value::Str(Rc::from("1"))), 2);
+        assert_eq!(code.add_constant(Value::Num(0.0)), 3);
+        assert_eq!(code.add_constant(Value::Num(-0.0)), 4);
+        assert_eq!(code.constants.len(), 5);
+        assert_eq!(&**code.fetch_name(1), "a");
     }
 }
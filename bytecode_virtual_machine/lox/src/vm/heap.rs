@@ -0,0 +1,465 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use super::value::{BoundMethod, Class, Closure, Function, Instance, Upvalue, Value};
+
+/// the first collection happens once this many bytes are allocated
+const FIRST_GC_THRESHOLD: usize = 1024 * 1024;
+/// the next collection happens once the live bytes have grown by this factor
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Object that can take part in a reference cycle, which reference counting alone never frees
+enum Object {
+    Closure(Weak<Closure>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+    Class(Weak<RefCell<Class>>),
+    Instance(Weak<RefCell<Instance>>),
+    BoundMethod(Weak<BoundMethod>),
+}
+impl Object {
+    fn address(&self) -> usize {
+        match self {
+            Object::Closure(object) => object.as_ptr() as *const () as usize,
+            Object::Upvalue(object) => object.as_ptr() as *const () as usize,
+            Object::Class(object) => object.as_ptr() as *const () as usize,
+            Object::Instance(object) => object.as_ptr() as *const () as usize,
+            Object::BoundMethod(object) => object.as_ptr() as *const () as usize,
+        }
+    }
+    fn is_alive(&self) -> bool {
+        match self {
+            Object::Closure(object) => object.strong_count() > 0,
+            Object::Upvalue(object) => object.strong_count() > 0,
+            Object::Class(object) => object.strong_count() > 0,
+            Object::Instance(object) => object.strong_count() > 0,
+            Object::BoundMethod(object) => object.strong_count() > 0,
+        }
+    }
+    fn upgrade(&self) -> Option<Strong> {
+        match self {
+            Object::Closure(object) => object.upgrade().map(|o| Strong::Value(Value::Closure(o))),
+            Object::Upvalue(object) => object.upgrade().map(Strong::Upvalue),
+            Object::Class(object) => object.upgrade().map(|o| Strong::Value(Value::Class(o))),
+            Object::Instance(object) => object.upgrade().map(|o| Strong::Value(Value::Instance(o))),
+            Object::BoundMethod(object) => object
+                .upgrade()
+                .map(|o| Strong::Value(Value::BoundMethod(o))),
+        }
+    }
+}
+
+/// Tracked object kept alive while the collector inspects it
+enum Strong {
+    Value(Value),
+    Upvalue(Rc<RefCell<Upvalue>>),
+}
+impl Strong {
+    fn address(&self) -> usize {
+        match self {
+            Strong::Value(value) => value_address(value).expect("tracked values are objects"),
+            Strong::Upvalue(upvalue) => address(upvalue),
+        }
+    }
+    fn strong_count(&self) -> usize {
+        match self {
+            Strong::Value(Value::Closure(closure)) => Rc::strong_count(closure),
+            Strong::Value(Value::Class(class)) => Rc::strong_count(class),
+            Strong::Value(Value::Instance(instance)) => Rc::strong_count(instance),
+            Strong::Value(Value::BoundMethod(bound)) => Rc::strong_count(bound),
+            Strong::Value(_) => unreachable!("only objects are tracked"),
+            Strong::Upvalue(upvalue) => Rc::strong_count(upvalue),
+        }
+    }
+    /// addresses of the tracked objects this one refers to, once per reference
+    fn references(&self) -> Vec<usize> {
+        let mut references = Vec::new();
+        match self {
+            Strong::Value(Value::Closure(closure)) => {
+                references.extend(closure.upvalues.iter().map(address));
+            }
+            Strong::Value(Value::Class(class)) => {
+                references.extend(class.borrow().methods.values().map(address));
+            }
+            Strong::Value(Value::Instance(instance)) => {
+                let instance = instance.borrow();
+                references.push(address(&instance.class));
+                references.extend(instance.fields.values().filter_map(value_address));
+            }
+            Strong::Value(Value::BoundMethod(bound)) => {
+                references.extend(value_address(&bound.receiver));
+                references.push(address(&bound.method));
+            }
+            Strong::Value(_) => (),
+            Strong::Upvalue(upvalue) => {
+                if let Upvalue::Closed(value) = &*upvalue.borrow() {
+                    references.extend(value_address(value));
+                }
+            }
+        }
+        references
+    }
+    fn mark(&self, marker: &mut Marker) {
+        match self {
+            Strong::Value(value) => marker.mark_value(value),
+            Strong::Upvalue(upvalue) => marker.mark_upvalue(upvalue),
+        }
+    }
+    /// drops the references held by the object, which breaks every cycle it's part of.
+    /// Closures and bound methods are immutable, they're freed along with the cells in their cycle.
+    fn clear(&self) {
+        match self {
+            Strong::Upvalue(upvalue) => *upvalue.borrow_mut() = Upvalue::Closed(Value::Nil),
+            Strong::Value(Value::Class(class)) => class.borrow_mut().methods.clear(),
+            Strong::Value(Value::Instance(instance)) => instance.borrow_mut().fields.clear(),
+            Strong::Value(_) => (),
+        }
+    }
+}
+
+struct Tracked {
+    object: Object,
+    /// approximate number of bytes allocated for the object
+    size: usize,
+}
+
+fn address<T: ?Sized>(object: &Rc<T>) -> usize {
+    Rc::as_ptr(object) as *const () as usize
+}
+
+/// address of the object held by the value, for the values that can take part in a cycle
+fn value_address(value: &Value) -> Option<usize> {
+    match value {
+        Value::Closure(closure) => Some(address(closure)),
+        Value::Class(class) => Some(address(class)),
+        Value::Instance(instance) => Some(address(instance)),
+        Value::BoundMethod(bound) => Some(address(bound)),
+        // strings, functions and natives never refer to the objects above
+        _ => None,
+    }
+}
+
+fn string_size(s: &str) -> usize {
+    // the string and the reference counts in front of it
+    s.len() + 2 * mem::size_of::<usize>()
+}
+
+/// Objects shared by the values of a running program.
+/// Strings are interned: every distinct string is allocated once,
+/// so copying a string value is a reference count increment and equal strings share a pointer.
+///
+/// Reference counting frees most objects as soon as they're unused, the tracing collector
+/// frees the reference cycles and the interned strings nothing else refers to.
+pub struct Heap {
+    strings: HashSet<Rc<str>>,
+    objects: Vec<Tracked>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// collecting after every allocation flushes out objects the collector fails to reach
+    #[cfg(feature = "gc_stress")]
+    allocated_since_gc: bool,
+}
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            strings: HashSet::new(),
+            objects: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: FIRST_GC_THRESHOLD,
+            #[cfg(feature = "gc_stress")]
+            allocated_since_gc: false,
+        }
+    }
+    /// returns the interned copy of the string, allocating it the first time it's seen
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(interned) = self.strings.get(s) {
+            return interned.clone();
+        }
+        let interned: Rc<str> = Rc::from(s);
+        self.strings.insert(interned.clone());
+        self.allocated(string_size(s));
+        interned
+    }
+    /// swaps the string constants of a compiled function and of the functions declared in it
+    /// for their interned copies, so that they compare equal to the strings built at runtime
+    pub fn intern_constants(&mut self, function: &mut Function) {
+        for constant in function.code.constants.iter_mut() {
+            match constant {
+                Value::Str(s) => *s = self.intern(s),
+                Value::Function(function) => {
+                    let function = Rc::get_mut(function)
+                        .expect("functions aren't shared before the program is loaded");
+                    self.intern_constants(function);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    pub fn alloc_closure(&mut self, closure: Closure) -> Rc<Closure> {
+        let size = mem::size_of::<Closure>()
+            + closure.upvalues.len() * mem::size_of::<Rc<RefCell<Upvalue>>>();
+        let closure = Rc::new(closure);
+        self.track(Object::Closure(Rc::downgrade(&closure)), size);
+        closure
+    }
+    pub fn alloc_upvalue(&mut self, upvalue: Upvalue) -> Rc<RefCell<Upvalue>> {
+        let upvalue = Rc::new(RefCell::new(upvalue));
+        let size = mem::size_of::<RefCell<Upvalue>>();
+        self.track(Object::Upvalue(Rc::downgrade(&upvalue)), size);
+        upvalue
+    }
+    pub fn alloc_class(&mut self, class: Class) -> Rc<RefCell<Class>> {
+        let class = Rc::new(RefCell::new(class));
+        let size = mem::size_of::<RefCell<Class>>();
+        self.track(Object::Class(Rc::downgrade(&class)), size);
+        class
+    }
+    pub fn alloc_instance(&mut self, instance: Instance) -> Rc<RefCell<Instance>> {
+        let instance = Rc::new(RefCell::new(instance));
+        let size = mem::size_of::<RefCell<Instance>>();
+        self.track(Object::Instance(Rc::downgrade(&instance)), size);
+        instance
+    }
+    pub fn alloc_bound_method(&mut self, bound: BoundMethod) -> Rc<BoundMethod> {
+        let bound = Rc::new(bound);
+        let size = mem::size_of::<BoundMethod>();
+        self.track(Object::BoundMethod(Rc::downgrade(&bound)), size);
+        bound
+    }
+    fn track(&mut self, object: Object, size: usize) {
+        self.objects.push(Tracked { object, size });
+        self.allocated(size);
+    }
+    fn allocated(&mut self, size: usize) {
+        self.bytes_allocated += size;
+        #[cfg(feature = "gc_stress")]
+        {
+            self.allocated_since_gc = true;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn tracked_objects(&self) -> usize {
+        self.objects.len()
+    }
+    #[cfg(test)]
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn should_collect(&self) -> bool {
+        #[cfg(feature = "gc_stress")]
+        return self.allocated_since_gc;
+        #[cfg(not(feature = "gc_stress"))]
+        return self.bytes_allocated > self.next_gc;
+    }
+    /// frees the objects the marker didn't reach from the roots, if only reference cycles
+    /// keep them alive. An unreached object still referenced from outside the heap,
+    /// eg. by a native function or a local of the VM, is kept with every object it refers to.
+    pub fn collect(&mut self, mut marker: Marker) {
+        marker.trace();
+        #[cfg(feature = "debug_exec_trace")]
+        let before = self.bytes_allocated;
+
+        let unreached: Vec<Strong> = self
+            .objects
+            .iter()
+            .filter(|tracked| !marker.marked.contains(&tracked.object.address()))
+            .filter_map(|tracked| tracked.object.upgrade())
+            .collect();
+        let mut internal_references: HashMap<usize, usize> = HashMap::new();
+        for object in &unreached {
+            for reference in object.references() {
+                *internal_references.entry(reference).or_default() += 1;
+            }
+        }
+        // the references not coming from unreached objects, besides the one in `unreached`
+        let externally_referenced: Vec<&Strong> = unreached
+            .iter()
+            .filter(|object| {
+                let internal = internal_references.get(&object.address()).unwrap_or(&0);
+                object.strong_count() > internal + 1
+            })
+            .collect();
+        for object in externally_referenced {
+            object.mark(&mut marker);
+        }
+        marker.trace();
+        for object in &unreached {
+            if !marker.marked.contains(&object.address()) {
+                object.clear();
+            }
+        }
+        drop(unreached);
+        self.objects.retain(|tracked| tracked.object.is_alive());
+        // the table itself holds one reference to every interned string
+        self.strings.retain(|s| Rc::strong_count(s) > 1);
+
+        self.bytes_allocated = self
+            .objects
+            .iter()
+            .map(|tracked| tracked.size)
+            .sum::<usize>()
+            + self.strings.iter().map(|s| string_size(s)).sum::<usize>();
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(FIRST_GC_THRESHOLD);
+        #[cfg(feature = "gc_stress")]
+        {
+            self.allocated_since_gc = false;
+        }
+        #[cfg(feature = "debug_exec_trace")]
+        println!(
+            "-- gc: {} -> {} bytes, next at {}",
+            before, self.bytes_allocated, self.next_gc
+        );
+    }
+}
+
+/// Finds the objects reachable from the roots, starting with the roots marked by the VM
+pub struct Marker {
+    marked: HashSet<usize>,
+    /// marked objects whose references aren't marked yet
+    gray: Vec<Value>,
+}
+impl Marker {
+    pub fn new() -> Self {
+        Marker {
+            marked: HashSet::new(),
+            gray: Vec::new(),
+        }
+    }
+    pub fn mark_value(&mut self, value: &Value) {
+        let Some(address) = value_address(value) else {
+            return;
+        };
+        if self.marked.insert(address) {
+            self.gray.push(value.clone());
+        }
+    }
+    pub fn mark_closure(&mut self, closure: &Rc<Closure>) {
+        self.mark_value(&Value::Closure(closure.clone()));
+    }
+    pub fn mark_upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>) {
+        if self.marked.insert(address(upvalue)) {
+            if let Upvalue::Closed(value) = &*upvalue.borrow() {
+                self.mark_value(value);
+            }
+        }
+    }
+    /// marks the objects referenced by the marked ones until no new object is reached
+    fn trace(&mut self) {
+        while let Some(value) = self.gray.pop() {
+            match value {
+                Value::Closure(closure) => {
+                    for upvalue in &closure.upvalues {
+                        self.mark_upvalue(upvalue);
+                    }
+                }
+                Value::Class(class) => {
+                    for method in class.borrow().methods.values() {
+                        self.mark_closure(method);
+                    }
+                }
+                Value::Instance(instance) => {
+                    let instance = instance.borrow();
+                    self.mark_value(&Value::Class(instance.class.clone()));
+                    for field in instance.fields.values() {
+                        self.mark_value(field);
+                    }
+                }
+                Value::BoundMethod(bound) => {
+                    self.mark_value(&bound.receiver);
+                    self.mark_closure(&bound.method);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_strings_are_interned_once() {
+        let mut heap = Heap::new();
+        let a = heap.intern("lox");
+        let b = heap.intern(&format!("l{}", "ox"));
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &heap.intern("Lox")));
+
+        let mut function = Function::new("script".to_string());
+        let index = function.code.add_constant(Value::Str(Rc::from("lox")));
+        heap.intern_constants(&mut function);
+        assert_eq!(function.code.fetch_constant(index), &Value::Str(a));
+    }
+
+    #[test]
+    fn unreachable_cycles_are_collected() {
+        let mut heap = Heap::new();
+        let name = heap.intern("A");
+        let class = heap.alloc_class(Class::new(name));
+        let field = heap.intern("me");
+        let mut instances = Vec::new();
+        for _ in 0..2 {
+            let instance = heap.alloc_instance(Instance::new(class.clone()));
+            let value = Value::Instance(instance.clone());
+            instance.borrow_mut().fields.insert(field.clone(), value);
+            instances.push(Rc::downgrade(&instance));
+        }
+        let root = Value::Instance(instances[0].upgrade().unwrap());
+        drop(field);
+
+        let mut marker = Marker::new();
+        marker.mark_value(&root);
+        heap.collect(marker);
+        // only the instance which isn't reachable from the roots is freed
+        assert!(instances[0].upgrade().is_some());
+        assert!(instances[1].upgrade().is_none());
+        assert_eq!(heap.objects.len(), 2);
+        assert_eq!(heap.strings.len(), 2);
+
+        drop(root);
+        heap.collect(Marker::new());
+        assert!(instances[0].upgrade().is_none());
+        // the class is still referenced by this test
+        assert_eq!(heap.objects.len(), 1);
+        assert_eq!(heap.strings.len(), 1);
+    }
+
+    #[test]
+    fn objects_referenced_outside_the_heap_are_kept() {
+        let mut heap = Heap::new();
+        let name = heap.intern("A");
+        let class = heap.alloc_class(Class::new(name));
+        let field = heap.intern("next");
+        let cycle = |heap: &mut Heap| {
+            let instance = heap.alloc_instance(Instance::new(class.clone()));
+            let value = Value::Instance(instance.clone());
+            instance.borrow_mut().fields.insert(field.clone(), value);
+            instance
+        };
+        // like a value held by a native function, unreachable from the roots
+        let held = cycle(&mut heap);
+        let inner = cycle(&mut heap);
+        let weak_inner = Rc::downgrade(&inner);
+        held.borrow_mut()
+            .fields
+            .insert(heap.intern("inner"), Value::Instance(inner));
+        let garbage = Rc::downgrade(&cycle(&mut heap));
+
+        heap.collect(Marker::new());
+        assert!(garbage.upgrade().is_none());
+        assert_eq!(held.borrow().fields.len(), 2);
+        let inner = weak_inner.upgrade().expect("reachable from a held object");
+        assert_eq!(inner.borrow().fields.len(), 1);
+        drop(inner);
+
+        drop(held);
+        heap.collect(Marker::new());
+        // the class is still referenced by this test
+        assert_eq!(heap.objects.len(), 1);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod bytecode;
+pub mod heap;
 pub mod value;
+#[allow(clippy::module_inception)]
 pub mod vm;
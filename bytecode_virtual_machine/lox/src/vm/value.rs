@@ -1,57 +1,183 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone, PartialEq)]
+use super::bytecode::ByteCode;
+
+#[derive(Debug, Clone)]
 pub enum Value {
     Num(f64),
     Bool(bool),
-    Str(String),
+    /// interned by the heap of the VM, see `Heap::intern`
+    Str(Rc<str>),
     Nil,
+    /// only found in the constant table, the `Closure` instruction wraps it at runtime
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Class(Rc<RefCell<Class>>),
+    Instance(Rc<RefCell<Instance>>),
+    BoundMethod(Rc<BoundMethod>),
+    Native(Rc<Native>),
 }
-impl Value {
-    pub fn is_num(&self) -> bool {
-        match self {
-            Value::Num(_) => true,
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Num(a), Value::Num(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            // interned strings are equal only if they're the same string
+            (Value::Str(a), Value::Str(b)) => Rc::ptr_eq(a, b),
+            (Value::Nil, Value::Nil) => true,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            // closures are only equal to themselves
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }
-    pub fn is_string(&self) -> bool {
+}
+impl Value {
+    /// nil and false are falsey, every other value is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Value::Str(_) => true,
-            _ => false,
+            Value::Nil => write!(f, "Nil"),
+            Value::Bool(v) => write!(f, "{v}"),
+            Value::Num(n) => write!(f, "{n}"),
+            Value::Str(s) => write!(f, "{s}"),
+            Value::Function(function) => write!(f, "{function}"),
+            Value::Closure(closure) => write!(f, "{}", closure.function),
+            Value::Class(class) => write!(f, "{}", class.borrow().name),
+            Value::Instance(instance) => {
+                write!(f, "{} instance", instance.borrow().class.borrow().name)
+            }
+            Value::BoundMethod(bound) => write!(f, "{}", bound.method.function),
+            Value::Native(native) => write!(f, "<native fn {}>", native.name),
         }
     }
-    pub fn is_bool(&self) -> bool {
-        match self {
-            Value::Bool(_) => true,
-            _ => false,
+}
+
+/// Compiled function, the top level code of a program is compiled into one as well.
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    /// number of (is_local, index) pairs following the `Closure` instruction
+    pub upvalue_count: usize,
+    pub code: ByteCode,
+}
+impl Function {
+    pub fn new(name: String) -> Self {
+        Function {
+            name,
+            arity: 0,
+            upvalue_count: 0,
+            code: ByteCode::new(),
         }
     }
-    pub fn get_num(&self) -> f64 {
-        match self {
-            Value::Num(v) => v.clone(),
-            _ => panic!("can't extract number from non number Value"),
+}
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<fn {}>", self.name)
+    }
+}
+
+/// Function together with the variables it captured from the enclosing functions.
+/// Functions are always wrapped in a closure at runtime, even when they capture nothing.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+impl Closure {
+    pub fn new(function: Rc<Function>) -> Self {
+        Closure {
+            function,
+            upvalues: Vec::new(),
         }
     }
-    pub fn get_string(&self) -> String {
-        match self {
-            Value::Str(s) => s.clone(),
-            _ => panic!("can't extract string from non string Value"),
+}
+
+/// Captured variable. It points to the stack slot while the variable is alive there,
+/// then holds the value itself once the slot is discarded.
+/// Closures capturing the same variable share the upvalue, so they see each other's assignments.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// Methods are added one by one by the instructions following the class declaration.
+#[derive(Debug)]
+pub struct Class {
+    pub name: Rc<str>,
+    pub methods: HashMap<Rc<str>, Rc<Closure>>,
+}
+impl Class {
+    pub fn new(name: Rc<str>) -> Self {
+        Class {
+            name,
+            methods: HashMap::new(),
         }
     }
-    pub fn get_bool(&self) -> bool {
-        match self {
-            Value::Bool(b) => b.clone(),
-            _ => panic!("can't extract boolean from non boolean Value)"),
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Rc<RefCell<Class>>,
+    pub fields: HashMap<Rc<str>, Value>,
+}
+impl Instance {
+    pub fn new(class: Rc<RefCell<Class>>) -> Self {
+        Instance {
+            class,
+            fields: HashMap::new(),
         }
     }
 }
-impl fmt::Display for Value {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Value::Nil => write!(f, "Nil"),
-            Value::Bool(v) => write!(f, "{v}"),
-            Value::Num(n) => write!(f, "{n}"),
-            Value::Str(s) => write!(f, "{s}"),
+
+/// Method accessed on an instance, calling it puts the receiver in the slot of `this`
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+/// Host function callable from lox code, an error message becomes a runtime error.
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, String>;
+
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: Box<NativeFn>,
+}
+impl Native {
+    pub fn new(name: String, arity: usize, function: Box<NativeFn>) -> Self {
+        Native {
+            name,
+            arity,
+            function,
         }
     }
 }
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+/// seconds since the unix epoch, mostly useful for benchmarking
+pub fn clock(_arguments: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::Num(now.as_secs_f64()))
+}
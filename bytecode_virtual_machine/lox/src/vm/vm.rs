@@ -1,7 +1,12 @@
-#![allow(dead_code)]
-use super::bytecode::ByteCode;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
 use super::bytecode::Opcode;
-use super::value::Value;
+use super::heap::{Heap, Marker};
+use super::value::{
+    clock, BoundMethod, Class, Closure, Function, Instance, Native, NativeFn, Upvalue, Value,
+};
 
 #[derive(Debug)]
 pub enum InterpretResult {
@@ -10,25 +15,75 @@ pub enum InterpretResult {
     RuntimeErr,
 }
 
-const STACK_LIMIT: usize = 256;
+const FRAMES_LIMIT: usize = 64;
+/// every frame can address 256 slots
+const STACK_LIMIT: usize = FRAMES_LIMIT * 256;
+
+/// A function call in progress
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// stack index of the called function, its arguments and locals follow it
+    slots: usize,
+}
+
 pub struct VM {
     stack: Vec<Value>,
-    ip: usize,
+    frames: Vec<CallFrame>,
+    /// kept between runs so that a REPL can refer to earlier declarations
+    globals: HashMap<Rc<str>, Value>,
+    /// upvalues still pointing to a stack slot, shared by every closure capturing that slot
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    heap: Heap,
     sp: usize,
 }
 
 impl VM {
     pub fn new() -> Self {
-        VM {
+        let mut vm = VM {
             stack: Vec::new(),
-            ip: 0,
+            frames: Vec::new(),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap: Heap::new(),
             sp: 0,
-        }
+        };
+        vm.register_native("clock", 0, Box::new(clock));
+        vm
+    }
+    /// defines a global function implemented by the host,
+    /// registering a name again replaces the previous function
+    pub fn register_native(&mut self, name: &str, arity: usize, function: Box<NativeFn>) {
+        let native = Native::new(name.to_string(), arity, function);
+        let name = self.heap.intern(name);
+        self.globals.insert(name, Value::Native(Rc::new(native)));
     }
-    pub fn interpret(&mut self, byte_code: &ByteCode) -> InterpretResult {
+    /// the value returned by the script isn't a garbage collection root of later runs,
+    /// keep it in a global to use its objects from lox again
+    pub fn interpret(&mut self, mut script: Function) -> InterpretResult {
         self.reset();
+        self.heap.intern_constants(&mut script);
+        let script = self.heap.alloc_closure(Closure::new(Rc::new(script)));
+        // the script occupies the first slot just like any called function
+        let result = self
+            .push(Value::Closure(script.clone()))
+            .and_then(|_| self.call(script, 0))
+            .and_then(|_| self.run());
+        match result {
+            Ok(value) => InterpretResult::Ok(value),
+            Err(error) => error,
+        }
+    }
+
+    fn run(&mut self) -> Result<Value, InterpretResult> {
         loop {
-            let instruction = byte_code.fetch_instruction(&mut self.ip);
+            // between two instructions every value in use is reachable from the roots
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+            let function = self.frame().closure.function.clone();
+            let byte_code = &function.code;
+            let instruction = byte_code.fetch_instruction(self.ip());
 
             #[cfg(feature = "debug_exec_trace")]
             {
@@ -40,178 +95,480 @@ impl VM {
                         .collect::<Vec<_>>()
                         .join(",")
                 });
-                byte_code.disasm_instruction(self.ip - 1);
+                byte_code.disasm_instruction(self.frame().ip - 1);
             }
 
             match instruction {
-                Opcode::Ret => return InterpretResult::Ok(self.stack.pop().unwrap()),
-                Opcode::Num => {
-                    let addr = byte_code.fetch_operand(&mut self.ip);
-                    let constant = byte_code.fetch_number(addr as usize);
-                    self.push(Value::Num(constant));
+                Opcode::Ret => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("returning from a call frame");
+                    self.close_upvalues(frame.slots);
+                    // discards the called function together with its arguments and locals
+                    self.stack.truncate(frame.slots);
+                    self.sp = frame.slots;
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.push(result)?;
+                }
+                Opcode::Closure | Opcode::ClosureLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let function = match byte_code.fetch_constant(addr) {
+                        Value::Function(function) => function,
+                        _ => panic!("closure instruction expects a function constant"),
+                    };
+                    let mut closure = Closure::new(function.clone());
+                    for _ in 0..function.upvalue_count {
+                        let is_local = byte_code.fetch_operand(self.ip()) == 1;
+                        let index = byte_code.fetch_operand(self.ip()) as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slots + index)
+                        } else {
+                            self.frame().closure.upvalues[index].clone()
+                        };
+                        closure.upvalues.push(upvalue);
+                    }
+                    let closure = self.heap.alloc_closure(closure);
+                    self.push(Value::Closure(closure))?;
+                }
+                Opcode::GetUpvalue => {
+                    let index = byte_code.fetch_operand(self.ip()) as usize;
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = match &*upvalue.borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.push(value)?;
+                }
+                Opcode::SetUpvalue => {
+                    let index = byte_code.fetch_operand(self.ip()) as usize;
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = self.peek().clone();
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                Opcode::CloseUpvalue => {
+                    // the captured local is about to leave the stack
+                    self.close_upvalues(self.sp - 1);
+                    self.pop();
+                }
+                Opcode::Call => {
+                    let arg_count = byte_code.fetch_operand(self.ip()) as usize;
+                    let callee = self.stack[self.sp - 1 - arg_count].clone();
+                    self.call_value(callee, arg_count)?;
+                }
+                Opcode::Class | Opcode::ClassLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    let class = Class::new(name.clone());
+                    let class = self.heap.alloc_class(class);
+                    self.push(Value::Class(class))?;
+                }
+                Opcode::Method | Opcode::MethodLong => {
+                    // the class stays on the stack until all of its methods are added
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    let method = match self.pop() {
+                        Value::Closure(closure) => closure,
+                        _ => panic!("method is not a closure"),
+                    };
+                    match self.peek() {
+                        Value::Class(class) => {
+                            class.borrow_mut().methods.insert(name.clone(), method);
+                        }
+                        _ => panic!("methods can only be added to a class"),
+                    }
+                }
+                Opcode::GetProperty | Opcode::GetPropertyLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    let instance = match self.peek() {
+                        Value::Instance(instance) => instance.clone(),
+                        _ => return Err(self.runtime_error("only instances have properties")),
+                    };
+                    // fields shadow methods
+                    let field = instance.borrow().fields.get(name).cloned();
+                    let value = match field {
+                        Some(value) => value,
+                        None => {
+                            let class = instance.borrow().class.clone();
+                            self.bind_method(&class, name)?
+                        }
+                    };
+                    self.pop();
+                    self.push(value)?;
+                }
+                Opcode::SetProperty | Opcode::SetPropertyLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    let value = self.pop();
+                    match self.pop() {
+                        Value::Instance(instance) => {
+                            let mut instance = instance.borrow_mut();
+                            instance.fields.insert(name.clone(), value.clone());
+                        }
+                        _ => return Err(self.runtime_error("only instances have fields")),
+                    }
+                    self.push(value)?;
+                }
+                Opcode::Inherit => {
+                    // the superclass stays on the stack as the `super` local of the methods
+                    let subclass = self.pop();
+                    let superclass = match self.peek() {
+                        Value::Class(superclass) => superclass.clone(),
+                        _ => return Err(self.runtime_error("superclass must be a class")),
+                    };
+                    if let Value::Class(subclass) = subclass {
+                        // copied before the subclass' own methods are added, which override them
+                        let methods = superclass.borrow().methods.clone();
+                        subclass.borrow_mut().methods.extend(methods);
+                    }
+                }
+                Opcode::GetSuper | Opcode::GetSuperLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    let superclass = match self.pop() {
+                        Value::Class(superclass) => superclass,
+                        _ => panic!("super is not a class"),
+                    };
+                    let method = self.bind_method(&superclass, name)?;
+                    self.pop();
+                    self.push(method)?;
+                }
+                Opcode::SuperInvoke | Opcode::SuperInvokeLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    let arg_count = byte_code.fetch_operand(self.ip()) as usize;
+                    let superclass = match self.pop() {
+                        Value::Class(superclass) => superclass,
+                        _ => panic!("super is not a class"),
+                    };
+                    self.invoke_from_class(&superclass, name, arg_count)?;
+                }
+                Opcode::Invoke | Opcode::InvokeLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    let arg_count = byte_code.fetch_operand(self.ip()) as usize;
+                    self.invoke(name, arg_count)?;
+                }
+                Opcode::Constant | Opcode::ConstantLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let constant = byte_code.fetch_constant(addr);
+                    self.push(constant.clone())?;
+                }
+                Opcode::Nil => {
+                    self.push(Value::Nil)?;
                 }
-                Opcode::Str => {
-                    let addr = byte_code.fetch_operand(&mut self.ip);
-                    let constant = byte_code.fetch_string(addr as usize);
-                    self.push(Value::Str(constant.clone()));
+                Opcode::Pop => {
+                    self.pop();
+                }
+                Opcode::Print => {
+                    println!("{}", self.pop());
+                }
+                Opcode::DefineGlobal | Opcode::DefineGlobalLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    let value = self.pop();
+                    self.globals.insert(name.clone(), value);
+                }
+                Opcode::GetGlobal | Opcode::GetGlobalLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    match self.globals.get(name) {
+                        Some(value) => self.push(value.clone())?,
+                        None => {
+                            return Err(self.runtime_error(&format!("undefined variable '{name}'")))
+                        }
+                    }
+                }
+                Opcode::SetGlobal | Opcode::SetGlobalLong => {
+                    let addr = byte_code.fetch_constant_index(instruction, self.ip());
+                    let name = byte_code.fetch_name(addr);
+                    // assignment is an expression, so the value stays on the stack
+                    let value = self.peek().clone();
+                    match self.globals.get_mut(name) {
+                        Some(slot) => *slot = value,
+                        None => {
+                            return Err(self.runtime_error(&format!("undefined variable '{name}'")))
+                        }
+                    }
+                }
+                Opcode::GetLocal => {
+                    let slot = byte_code.fetch_operand(self.ip()) as usize;
+                    let value = self.stack[self.frame().slots + slot].clone();
+                    self.push(value)?;
+                }
+                Opcode::SetLocal => {
+                    let slot = byte_code.fetch_operand(self.ip()) as usize;
+                    let base = self.frame().slots;
+                    self.stack[base + slot] = self.peek().clone();
+                }
+                Opcode::Jump => {
+                    let offset = byte_code.fetch_short(self.ip());
+                    *self.ip() += offset as usize;
+                }
+                Opcode::JumpIfFalse => {
+                    let offset = byte_code.fetch_short(self.ip());
+                    if self.peek().is_falsey() {
+                        *self.ip() += offset as usize;
+                    }
+                }
+                Opcode::Loop => {
+                    let offset = byte_code.fetch_short(self.ip());
+                    *self.ip() -= offset as usize;
                 }
                 Opcode::Neg => match self.pop() {
-                    Value::Num(v) => self.push(Value::Num(-v)),
-                    _ => panic!("Negate only works on number"),
+                    Value::Num(v) => self.push(Value::Num(-v))?,
+                    _ => return Err(self.runtime_error("operand must be a number")),
                 },
                 Opcode::Add => {
                     let b = self.pop();
                     let a = self.pop();
-                    if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Num(a + b));
-                    } else if a.is_string() && b.is_string() {
-                        let a = a.get_string();
-                        let b = b.get_string();
-                        let result = format!("{}{}", a, b);
-                        self.push(Value::Str(result));
-                    } else {
-                        panic!("only numnbers can be added")
+                    match (&a, &b) {
+                        (Value::Num(a), Value::Num(b)) => self.push(Value::Num(a + b))?,
+                        (Value::Str(a), Value::Str(b)) => {
+                            let mut result = String::with_capacity(a.len() + b.len());
+                            result.push_str(a);
+                            result.push_str(b);
+                            let result = self.heap.intern(&result);
+                            self.push(Value::Str(result))?;
+                        }
+                        _ => {
+                            return Err(
+                                self.runtime_error("operands must be two numbers or two strings")
+                            )
+                        }
                     }
                 }
                 Opcode::Sub => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Num(a - b));
-                    } else {
-                        panic!("only numnbers can be substracted")
-                    }
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Num(a - b))?;
                 }
                 Opcode::Mul => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Num(a * b));
-                    } else {
-                        panic!("only numnbers can be multiplied")
-                    }
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Num(a * b))?;
                 }
                 Opcode::Div => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Num(a / b));
-                    } else {
-                        panic!("only numnbers can be divided")
-                    }
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Num(a / b))?;
                 }
                 Opcode::True => {
-                    self.push(Value::Bool(true));
+                    self.push(Value::Bool(true))?;
                 }
                 Opcode::False => {
-                    self.push(Value::Bool(false));
+                    self.push(Value::Bool(false))?;
                 }
                 Opcode::Not => {
                     let v = self.pop();
-                    match v {
-                        Value::Bool(b) => {
-                            self.push(Value::Bool(!b));
-                        }
-                        _ => {
-                            panic!("Not operator only works on boolean value")
-                        }
-                    }
+                    self.push(Value::Bool(v.is_falsey()))?;
                 }
                 Opcode::Less => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Bool(a < b));
-                    } else {
-                        panic!("only numnbers can be compared with <")
-                    }
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Bool(a < b))?;
                 }
                 Opcode::LessEqual => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Bool(a <= b));
-                    } else {
-                        panic!("only numnbers can be compared with <=")
-                    }
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Bool(a <= b))?;
                 }
                 Opcode::Greater => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Bool(a > b));
-                    } else {
-                        panic!("only numnbers can be compared with >")
-                    }
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Bool(a > b))?;
                 }
                 Opcode::GreaterEqual => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Bool(a >= b));
-                    } else {
-                        panic!("only numnbers can be compared with >=")
-                    }
+                    let (a, b) = self.pop_numbers()?;
+                    self.push(Value::Bool(a >= b))?;
                 }
+                // values of different types are never equal
                 Opcode::Equal => {
                     let b = self.pop();
                     let a = self.pop();
-                    if a.is_bool() && b.is_bool() {
-                        let a = a.get_bool();
-                        let b = b.get_bool();
-                        self.push(Value::Bool(a == b));
-                    } else if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Bool(a == b));
-                    } else if a.is_string() && b.is_string() {
-                        let a = a.get_string();
-                        let b = b.get_string();
-                        self.push(Value::Bool(a == b));
-                    } else {
-                        panic!("only booleans and numbers can be compared with ==")
-                    }
+                    self.push(Value::Bool(a == b))?;
                 }
                 Opcode::NotEqual => {
                     let b = self.pop();
                     let a = self.pop();
-                    if a.is_bool() && b.is_bool() {
-                        let a = a.get_bool();
-                        let b = b.get_bool();
-                        self.push(Value::Bool(a != b));
-                    } else if a.is_num() && b.is_num() {
-                        let a = a.get_num();
-                        let b = b.get_num();
-                        self.push(Value::Bool(a != b));
-                    } else {
-                        panic!("only booleans and numbers can be compared with !=")
+                    self.push(Value::Bool(a != b))?;
+                }
+            }
+        }
+    }
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretResult> {
+        match callee {
+            Value::Closure(closure) => self.call(closure, arg_count),
+            Value::Class(class) => {
+                // the instance replaces the class and becomes `this` of the initializer
+                let instance = self.heap.alloc_instance(Instance::new(class.clone()));
+                self.stack[self.sp - 1 - arg_count] = Value::Instance(instance);
+                let initializer = class.borrow().methods.get("init").cloned();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => {
+                        Err(self
+                            .runtime_error(&format!("expected 0 arguments but got {arg_count}")))
                     }
+                    None => Ok(()),
                 }
             }
+            Value::BoundMethod(bound) => {
+                self.stack[self.sp - 1 - arg_count] = bound.receiver.clone();
+                self.call(bound.method.clone(), arg_count)
+            }
+            Value::Native(native) => {
+                if arg_count != native.arity {
+                    return Err(self.runtime_error(&format!(
+                        "expected {} arguments but got {}",
+                        native.arity, arg_count
+                    )));
+                }
+                // natives run straight away, without a call frame
+                let result = (native.function)(&self.stack[self.sp - arg_count..self.sp]);
+                match result {
+                    Ok(value) => {
+                        // natives build their strings outside of the heap
+                        let value = match value {
+                            Value::Str(s) => Value::Str(self.heap.intern(&s)),
+                            value => value,
+                        };
+                        let callee_slot = self.sp - 1 - arg_count;
+                        self.stack.truncate(callee_slot);
+                        self.sp = callee_slot;
+                        self.push(value)
+                    }
+                    Err(message) => Err(self.runtime_error(&message)),
+                }
+            }
+            _ => Err(self.runtime_error("can only call functions and classes")),
+        }
+    }
+
+    /// calls a method straight away, without creating the bound method `GetProperty` would
+    fn invoke(&mut self, name: &str, arg_count: usize) -> Result<(), InterpretResult> {
+        let instance = match &self.stack[self.sp - 1 - arg_count] {
+            Value::Instance(instance) => instance.clone(),
+            _ => return Err(self.runtime_error("only instances have methods")),
+        };
+        // a field holding a function is called like any other value
+        let field = instance.borrow().fields.get(name).cloned();
+        if let Some(field) = field {
+            self.stack[self.sp - 1 - arg_count] = field.clone();
+            return self.call_value(field, arg_count);
+        }
+        let class = instance.borrow().class.clone();
+        self.invoke_from_class(&class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: &Rc<RefCell<Class>>,
+        name: &str,
+        arg_count: usize,
+    ) -> Result<(), InterpretResult> {
+        let method = class.borrow().methods.get(name).cloned();
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => Err(self.runtime_error(&format!("undefined property '{name}'"))),
+        }
+    }
+
+    /// binds the method to the receiver on top of the stack
+    fn bind_method(
+        &mut self,
+        class: &Rc<RefCell<Class>>,
+        name: &str,
+    ) -> Result<Value, InterpretResult> {
+        let method = class.borrow().methods.get(name).cloned();
+        match method {
+            Some(method) => {
+                let receiver = self.peek().clone();
+                let bound = self
+                    .heap
+                    .alloc_bound_method(BoundMethod { receiver, method });
+                Ok(Value::BoundMethod(bound))
+            }
+            None => Err(self.runtime_error(&format!("undefined property '{name}'"))),
         }
     }
-    fn push(&mut self, v: Value) {
+
+    fn call(&mut self, closure: Rc<Closure>, arg_count: usize) -> Result<(), InterpretResult> {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            return Err(
+                self.runtime_error(&format!("expected {arity} arguments but got {arg_count}"))
+            );
+        }
+        if self.frames.len() >= FRAMES_LIMIT {
+            return Err(self.runtime_error("stack overflow"));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.sp - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    /// reuses the upvalue of the slot if another closure already captured it
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self
+            .open_upvalues
+            .iter()
+            .find(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(open) if open == slot));
+        if let Some(upvalue) = existing {
+            return upvalue.clone();
+        }
+        let upvalue = self.heap.alloc_upvalue(Upvalue::Open(slot));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    /// moves the values of the slots from `first_slot` upwards into their upvalues
+    fn close_upvalues(&mut self, first_slot: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open(slot) => slot,
+                Upvalue::Closed(_) => return false,
+            };
+            if slot < first_slot {
+                return true;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(stack[slot].clone());
+            false
+        });
+    }
+
+    /// marks the roots: the values on the stack, the globals,
+    /// the closures being run and the upvalues still pointing to the stack
+    fn collect_garbage(&mut self) {
+        let mut marker = Marker::new();
+        for value in self.stack.iter().chain(self.globals.values()) {
+            marker.mark_value(value);
+        }
+        for frame in &self.frames {
+            marker.mark_closure(&frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            marker.mark_upvalue(upvalue);
+        }
+        self.heap.collect(marker);
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no function is running")
+    }
+    fn ip(&mut self) -> &mut usize {
+        &mut self.frames.last_mut().expect("no function is running").ip
+    }
+
+    fn push(&mut self, v: Value) -> Result<(), InterpretResult> {
         if self.sp >= STACK_LIMIT {
-            panic!("stackoverflow");
+            return Err(self.runtime_error("stack overflow"));
         }
         self.stack.push(v);
         self.sp += 1;
+        Ok(())
     }
     fn pop(&mut self) -> Value {
         if self.sp == 0 {
@@ -220,9 +577,107 @@ impl VM {
         self.sp -= 1;
         self.stack.pop().unwrap()
     }
+    /// pops the operands of an arithmetic or comparison operator
+    fn pop_numbers(&mut self) -> Result<(f64, f64), InterpretResult> {
+        let b = self.pop();
+        let a = self.pop();
+        match (a, b) {
+            (Value::Num(a), Value::Num(b)) => Ok((a, b)),
+            _ => Err(self.runtime_error("operands must be numbers")),
+        }
+    }
+    fn peek(&self) -> &Value {
+        if self.sp == 0 {
+            panic!("stack underflow");
+        }
+        &self.stack[self.sp - 1]
+    }
+
+    /// reports the error with the line of the instruction being executed
+    /// followed by the calls leading to it, and unwinds the stack
+    fn runtime_error(&mut self, message: &str) -> InterpretResult {
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            let line = frame.closure.function.code.line_info[frame.ip - 1];
+            if depth == self.frames.len() - 1 {
+                eprintln!("[line {line}] runtime error: {message}");
+            }
+            if depth == 0 {
+                eprintln!("    [line {line}] in script");
+            } else {
+                eprintln!("    [line {line}] in {}()", frame.closure.function.name);
+            }
+        }
+        self.reset();
+        InterpretResult::RuntimeErr
+    }
 
     fn reset(&mut self) {
-        self.ip = 0;
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
         self.sp = 0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::compile;
+    use crate::scanner::Scanner;
+
+    use super::*;
+
+    fn run(vm: &mut VM, source: &str) -> Value {
+        let mut scanner = Scanner::new(source);
+        let script = compile(scanner.scan_tokens()).expect("valid source");
+        match vm.interpret(script) {
+            InterpretResult::Ok(value) => value,
+            result => panic!("{source}\nunexpected result: {result:?}"),
+        }
+    }
+
+    #[test]
+    fn unreachable_cycles_are_collected() {
+        let mut vm = VM::new();
+        let source = "
+            class Node {}
+            var kept = Node();
+            kept.next = kept;
+            for (var i = 0; i < 100; i = i + 1) {
+                var cycle = Node();
+                cycle.next = cycle;
+            }";
+        run(&mut vm, source);
+        let (objects, bytes) = (vm.heap.tracked_objects(), vm.heap.bytes_allocated());
+
+        vm.collect_garbage();
+        // the class and the instance in a global
+        assert_eq!(vm.heap.tracked_objects(), 2);
+        // gc_stress already collected after every allocation
+        if !cfg!(feature = "gc_stress") {
+            assert!(objects > 100, "{objects} objects before the collection");
+            assert!(vm.heap.bytes_allocated() < bytes);
+        }
+        assert_eq!(run(&mut vm, "kept.next == kept;"), Value::Bool(true));
+    }
+
+    #[test]
+    fn allocations_trigger_collections() {
+        let mut vm = VM::new();
+        // the strings built by the loop add up to several collection thresholds
+        let source = format!(
+            "
+            class Node {{}}
+            var s = \"\";
+            for (var i = 0; i < 300; i = i + 1) {{
+                var cycle = Node();
+                cycle.next = cycle;
+                s = s + \"{}\";
+            }}",
+            "x".repeat(100)
+        );
+        run(&mut vm, &source);
+        let built: usize = (1..=300).map(|i| i * 100).sum();
+        assert!(vm.heap.tracked_objects() < 300);
+        assert!(vm.heap.bytes_allocated() < built);
+    }
+}
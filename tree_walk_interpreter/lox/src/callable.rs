@@ -0,0 +1,151 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::class::LoxInstance;
+use crate::environment::Environment;
+use crate::parser::statement::Function;
+use crate::parser::visitors::interpreter::{AstInterpreterVisitor, ReturnValue};
+
+/// Anything that can be called from lox code with `callee(args)`.
+/// Callables are stored in the interpreter as `Rc<dyn LoxCallable>`.
+pub trait LoxCallable {
+    fn arity(&self) -> usize;
+    fn call(
+        &self,
+        interpreter: &AstInterpreterVisitor,
+        arguments: Vec<Box<dyn Any>>,
+    ) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn name(&self) -> String;
+}
+
+/// Host function callable from lox code. Arguments and the result use the interpreter's
+/// runtime types: `f64`, `String`, `bool` and `()` for nil.
+pub type NativeFn = dyn Fn(Vec<Box<dyn Any>>) -> Result<Box<dyn Any>, Box<dyn Error>>;
+
+pub struct NativeFunction {
+    name: String,
+    arity: usize,
+    function: Box<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn new(name: String, arity: usize, function: Box<NativeFn>) -> Self {
+        NativeFunction {
+            name,
+            arity,
+            function,
+        }
+    }
+}
+
+impl LoxCallable for NativeFunction {
+    fn arity(&self) -> usize {
+        self.arity
+    }
+
+    fn call(
+        &self,
+        _interpreter: &AstInterpreterVisitor,
+        arguments: Vec<Box<dyn Any>>,
+    ) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let result = (self.function)(arguments)?;
+        // anything else would only fail later, far from the native that produced it
+        let runtime_types = [
+            TypeId::of::<f64>(),
+            TypeId::of::<String>(),
+            TypeId::of::<bool>(),
+            TypeId::of::<()>(),
+        ];
+        if !runtime_types.contains(&(*result).type_id()) {
+            return Err(format!(
+                "[RuntimeError] native function '{}' returned a value of an unsupported type",
+                self.name
+            )
+            .into());
+        }
+        Ok(result)
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// seconds since the unix epoch, mostly useful for benchmarking
+pub fn clock(_arguments: Vec<Box<dyn Any>>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    Ok(Box::new(now.as_secs_f64()))
+}
+
+pub struct LoxFunction {
+    declaration: Rc<Function>,
+    /// environment active where the function was declared
+    closure: Rc<RefCell<Environment>>,
+    /// `init` methods always return `this`
+    is_initializer: bool,
+}
+
+impl LoxFunction {
+    pub fn new(
+        declaration: Rc<Function>,
+        closure: Rc<RefCell<Environment>>,
+        is_initializer: bool,
+    ) -> Self {
+        LoxFunction {
+            declaration,
+            closure,
+            is_initializer,
+        }
+    }
+
+    /// creates a copy of the method whose closure has `this` bound to the instance
+    pub fn bind(&self, instance: Rc<RefCell<LoxInstance>>) -> LoxFunction {
+        let mut environment = Environment::new_enclosed(self.closure.clone());
+        environment.define("this".to_string(), Box::new(instance));
+        LoxFunction::new(
+            self.declaration.clone(),
+            Rc::new(RefCell::new(environment)),
+            self.is_initializer,
+        )
+    }
+
+    fn bound_this(&self) -> Box<dyn Any> {
+        Environment::get_at(&self.closure, 0, "this")
+            .expect("initializer is not bound to an instance")
+    }
+}
+
+impl LoxCallable for LoxFunction {
+    fn arity(&self) -> usize {
+        self.declaration.params.len()
+    }
+
+    fn call(
+        &self,
+        interpreter: &AstInterpreterVisitor,
+        arguments: Vec<Box<dyn Any>>,
+    ) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let mut environment = Environment::new_enclosed(self.closure.clone());
+        for (param, argument) in self.declaration.params.iter().zip(arguments) {
+            environment.define(param.lexeme.clone(), argument);
+        }
+        match interpreter.execute_block(&self.declaration.body, Rc::new(RefCell::new(environment)))
+        {
+            Ok(()) if self.is_initializer => Ok(self.bound_this()),
+            Ok(()) => Ok(Box::new(())),
+            // `return` unwinds the body as an error carrying the returned value
+            Err(e) => match e.downcast::<ReturnValue>() {
+                Ok(_) if self.is_initializer => Ok(self.bound_this()),
+                Ok(returned) => Ok(returned.value),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    fn name(&self) -> String {
+        self.declaration.name.lexeme.clone()
+    }
+}
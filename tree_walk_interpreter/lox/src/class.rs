@@ -0,0 +1,104 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::{Rc, Weak};
+
+use crate::callable::{LoxCallable, LoxFunction};
+use crate::parser::visitors::interpreter::{clone_value, AstInterpreterVisitor};
+
+/// Classes are stored in the interpreter as `Rc<LoxClass>`, calling one creates an instance.
+pub struct LoxClass {
+    pub name: String,
+    superclass: Option<Rc<LoxClass>>,
+    methods: HashMap<String, Rc<LoxFunction>>,
+    /// lets `call` hand out a strong reference to this class to the new instances
+    this: Weak<LoxClass>,
+}
+
+impl LoxClass {
+    pub fn new(
+        name: String,
+        superclass: Option<Rc<LoxClass>>,
+        methods: HashMap<String, Rc<LoxFunction>>,
+    ) -> Rc<LoxClass> {
+        Rc::new_cyclic(|this| LoxClass {
+            name,
+            superclass,
+            methods,
+            this: this.clone(),
+        })
+    }
+
+    /// looks up the method in this class and then up the superclass chain
+    pub fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        match self.methods.get(name) {
+            Some(method) => Some(method.clone()),
+            None => match &self.superclass {
+                Some(superclass) => superclass.find_method(name),
+                None => None,
+            },
+        }
+    }
+}
+
+impl LoxCallable for LoxClass {
+    fn arity(&self) -> usize {
+        match self.find_method("init") {
+            Some(initializer) => initializer.arity(),
+            None => 0,
+        }
+    }
+
+    fn call(
+        &self,
+        interpreter: &AstInterpreterVisitor,
+        arguments: Vec<Box<dyn Any>>,
+    ) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let class = self
+            .this
+            .upgrade()
+            .expect("class dropped while being called");
+        let instance = Rc::new(RefCell::new(LoxInstance::new(class)));
+        if let Some(initializer) = self.find_method("init") {
+            initializer
+                .bind(instance.clone())
+                .call(interpreter, arguments)?;
+        }
+        Ok(Box::new(instance))
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Instances are stored in the interpreter as `Rc<RefCell<LoxInstance>>`
+/// so that every reference sees the same fields.
+pub struct LoxInstance {
+    pub class: Rc<LoxClass>,
+    fields: HashMap<String, Box<dyn Any>>,
+}
+
+impl LoxInstance {
+    pub fn new(class: Rc<LoxClass>) -> Self {
+        LoxInstance {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+
+    /// fields shadow methods, methods are returned bound to the instance
+    pub fn get(instance: &Rc<RefCell<LoxInstance>>, name: &str) -> Option<Box<dyn Any>> {
+        if let Some(value) = instance.borrow().fields.get(name) {
+            return Some(clone_value(value));
+        }
+        let method = instance.borrow().class.find_method(name)?;
+        let method: Rc<dyn LoxCallable> = Rc::new(method.bind(instance.clone()));
+        Some(Box::new(method))
+    }
+
+    pub fn set(&mut self, name: String, value: Box<dyn Any>) {
+        self.fields.insert(name, value);
+    }
+}
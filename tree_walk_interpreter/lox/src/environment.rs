@@ -0,0 +1,100 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::parser::visitors::interpreter::clone_value;
+
+/// Each block gets its own environment linked to the environment of the surrounding block.
+/// Lookups and assignments walk up this chain until the variable is found.
+pub struct Environment {
+    values: HashMap<String, Box<dyn Any>>,
+    enclosing: Option<Rc<RefCell<Environment>>>,
+}
+
+impl Environment {
+    pub fn new() -> Self {
+        Environment {
+            values: HashMap::new(),
+            enclosing: None,
+        }
+    }
+
+    pub fn new_enclosed(enclosing: Rc<RefCell<Environment>>) -> Self {
+        Environment {
+            values: HashMap::new(),
+            enclosing: Some(enclosing),
+        }
+    }
+
+    pub fn define(&mut self, name: String, value: Box<dyn Any>) {
+        // redefining an existing variable is allowed, it simply overwrites the old value
+        self.values.insert(name, value);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Box<dyn Any>> {
+        match self.values.get(name) {
+            Some(value) => Some(clone_value(value)),
+            None => match &self.enclosing {
+                Some(enclosing) => enclosing.borrow().get(name),
+                None => None,
+            },
+        }
+    }
+
+    pub fn get_at(
+        environment: &Rc<RefCell<Environment>>,
+        distance: usize,
+        name: &str,
+    ) -> Option<Box<dyn Any>> {
+        let ancestor = Self::ancestor(environment, distance);
+        let ancestor = ancestor.borrow();
+        ancestor.values.get(name).map(clone_value)
+    }
+
+    pub fn assign_at(
+        environment: &Rc<RefCell<Environment>>,
+        distance: usize,
+        name: &str,
+        value: Box<dyn Any>,
+    ) -> bool {
+        let ancestor = Self::ancestor(environment, distance);
+        let mut ancestor = ancestor.borrow_mut();
+        match ancestor.values.get_mut(name) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn ancestor(
+        environment: &Rc<RefCell<Environment>>,
+        distance: usize,
+    ) -> Rc<RefCell<Environment>> {
+        let mut current = environment.clone();
+        for _ in 0..distance {
+            let enclosing = current
+                .borrow()
+                .enclosing
+                .clone()
+                .expect("resolved scope distance is deeper than the environment chain");
+            current = enclosing;
+        }
+        current
+    }
+
+    pub fn assign(&mut self, name: &str, value: Box<dyn Any>) -> bool {
+        match self.values.get_mut(name) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+            None => match &self.enclosing {
+                Some(enclosing) => enclosing.borrow_mut().assign(name, value),
+                None => false,
+            },
+        }
+    }
+}
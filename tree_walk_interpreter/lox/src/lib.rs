@@ -1,19 +1,34 @@
 use crate::{
-    parser::{visitors::interpreter::AstInterpreterVisitor, Parser},
+    parser::{visitors::resolver::Resolver, Parser},
     scanner::Scanner,
 };
 
+pub use crate::callable::NativeFn;
+pub use crate::parser::ast_printer::AstPrinterVisitor;
+pub use crate::parser::visitors::interpreter::AstInterpreterVisitor;
+
+mod callable;
+mod class;
+mod environment;
 mod error;
 mod parser;
 mod scanner;
 
-pub fn run(source: &String) {
+pub fn run(source: &str) {
+    run_with(&AstInterpreterVisitor::new(), source);
+}
+
+/// Runs the source with the given interpreter, eg. one with extra natives registered
+/// through `AstInterpreterVisitor::register_native`.
+pub fn run_with(interpreter: &AstInterpreterVisitor, source: &str) {
     println!("running: {}", source);
     let mut scanner = Scanner::new(source);
 
     let tokens = scanner.scan_tokens();
     let mut parser = Parser::new(tokens);
     let statements = parser.parse();
-    let interpreter = AstInterpreterVisitor::new();
+    if !Resolver::new().resolve(&statements) {
+        return;
+    }
     interpreter.interpret(statements);
 }
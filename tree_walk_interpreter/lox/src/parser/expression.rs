@@ -1,5 +1,6 @@
 use crate::scanner::token::{Token, TokenType};
 use std::any::Any;
+use std::cell::Cell;
 use std::error::Error;
 
 pub trait ExpressionVisitor {
@@ -7,10 +8,20 @@ pub trait ExpressionVisitor {
     fn for_binary(&self, expr: &Binary) -> Result<Box<dyn Any>, Box<dyn Error>>;
     fn for_grouping(&self, expr: &Grouping) -> Result<Box<dyn Any>, Box<dyn Error>>;
     fn for_literal(&self, expr: &Literal) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn for_identifier(&self, expr: &Identifier) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn for_assign(&self, expr: &Assign) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn for_logical(&self, expr: &Logical) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn for_call(&self, expr: &Call) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn for_get(&self, expr: &Get) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn for_set(&self, expr: &Set) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn for_this(&self, expr: &This) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    fn for_super(&self, expr: &Super) -> Result<Box<dyn Any>, Box<dyn Error>>;
 }
 
 pub trait Expr {
     fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>>;
+    /// lets the parser recover the concrete node, eg. to turn an identifier into an assignment target
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
 }
 
 pub struct Operator {
@@ -28,7 +39,10 @@ impl Operator {
             | TokenType::Plus
             | TokenType::Minus
             | TokenType::Star
-            | TokenType::Slash => Operator { token },
+            | TokenType::Slash
+            | TokenType::Bang
+            | TokenType::And
+            | TokenType::Or => Operator { token },
             _ => panic!("invalid token for operator"),
         }
     }
@@ -49,6 +63,96 @@ impl Binary {
     }
 }
 
+/// `and` / `or` are kept apart from `Binary` as they don't always evaluate the right operand
+pub struct Logical {
+    pub left: Box<dyn Expr>,
+    pub right: Box<dyn Expr>,
+    pub operator: Operator,
+}
+impl Logical {
+    pub fn new(left: Box<dyn Expr>, right: Box<dyn Expr>, operator: Operator) -> Logical {
+        Logical {
+            left,
+            right,
+            operator,
+        }
+    }
+}
+
+pub struct Call {
+    pub callee: Box<dyn Expr>,
+    /// closing paren, its line is used for the errors raised by the call
+    pub paren: Token,
+    pub arguments: Vec<Box<dyn Expr>>,
+}
+impl Call {
+    pub fn new(callee: Box<dyn Expr>, paren: Token, arguments: Vec<Box<dyn Expr>>) -> Call {
+        Call {
+            callee,
+            paren,
+            arguments,
+        }
+    }
+}
+
+/// property access `object.name`
+pub struct Get {
+    pub object: Box<dyn Expr>,
+    pub name: Token,
+}
+impl Get {
+    pub fn new(object: Box<dyn Expr>, name: Token) -> Get {
+        Get { object, name }
+    }
+}
+
+/// property assignment `object.name = value`
+pub struct Set {
+    pub object: Box<dyn Expr>,
+    pub name: Token,
+    pub value: Box<dyn Expr>,
+}
+impl Set {
+    pub fn new(object: Box<dyn Expr>, name: Token, value: Box<dyn Expr>) -> Set {
+        Set {
+            object,
+            name,
+            value,
+        }
+    }
+}
+
+pub struct This {
+    pub keyword: Token,
+    /// same as `Identifier::depth`
+    pub depth: Cell<Option<usize>>,
+}
+impl This {
+    pub fn new(keyword: Token) -> This {
+        This {
+            keyword,
+            depth: Cell::new(None),
+        }
+    }
+}
+
+/// `super.method` lookup, starting from the superclass of the enclosing class
+pub struct Super {
+    pub keyword: Token,
+    pub method: Token,
+    /// same as `Identifier::depth`
+    pub depth: Cell<Option<usize>>,
+}
+impl Super {
+    pub fn new(keyword: Token, method: Token) -> Super {
+        Super {
+            keyword,
+            method,
+            depth: Cell::new(None),
+        }
+    }
+}
+
 pub struct Literal {
     pub token: Token,
 }
@@ -97,26 +201,144 @@ impl Grouping {
     }
 }
 
+pub struct Identifier {
+    pub name: Token,
+    /// number of scopes between the usage and the declaration, filled in by the resolver.
+    /// `None` means the variable is a global
+    pub depth: Cell<Option<usize>>,
+}
+impl Identifier {
+    pub fn new(name: Token) -> Identifier {
+        match name.token_type {
+            TokenType::Identifier => Identifier {
+                name,
+                depth: Cell::new(None),
+            },
+            _ => panic!("invalid token for identifier"),
+        }
+    }
+}
+
+pub struct Assign {
+    pub name: Token,
+    pub value: Box<dyn Expr>,
+    /// same as `Identifier::depth`
+    pub depth: Cell<Option<usize>>,
+}
+impl Assign {
+    pub fn new(name: Token, value: Box<dyn Expr>) -> Assign {
+        Assign {
+            name,
+            value,
+            depth: Cell::new(None),
+        }
+    }
+}
+
 impl Expr for Binary {
     fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
         visitor.for_binary(self)
     }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
 }
 
 impl Expr for Unary {
     fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
         visitor.for_unary(self)
     }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
 }
 
 impl Expr for Grouping {
     fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
         visitor.for_grouping(self)
     }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
 }
 
 impl Expr for Literal {
     fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
         visitor.for_literal(self)
     }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Expr for Identifier {
+    fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        visitor.for_identifier(self)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Expr for Assign {
+    fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        visitor.for_assign(self)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Expr for Logical {
+    fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        visitor.for_logical(self)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Expr for Call {
+    fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        visitor.for_call(self)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Expr for Get {
+    fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        visitor.for_get(self)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Expr for Set {
+    fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        visitor.for_set(self)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Expr for This {
+    fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        visitor.for_this(self)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Expr for Super {
+    fn accept(&self, visitor: Box<dyn ExpressionVisitor>) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        visitor.for_super(self)
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
 }
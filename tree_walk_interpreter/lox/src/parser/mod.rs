@@ -1,11 +1,15 @@
 use core::fmt;
 use std::error::Error;
+use std::rc::Rc;
 
 use crate::scanner::token::{Token, TokenType};
 
 use self::{
-    expression::{Binary, Expr, Grouping, Literal, Operator, Unary},
-    statement::Stmt,
+    expression::{
+        Assign, Binary, Call, Expr, Get, Grouping, Identifier, Literal, Logical, Operator, Set,
+        Super, This, Unary,
+    },
+    statement::{Function, Stmt},
 };
 
 pub mod expression;
@@ -13,6 +17,8 @@ pub mod statement;
 pub mod visitors;
 pub use visitors::ast_printer;
 
+const MAX_ARGUMENTS: usize = 255;
+
 pub struct Parser<'a> {
     tokens: &'a Vec<Token>,
     current: usize,
@@ -32,13 +38,17 @@ impl fmt::Display for ParserError {
 impl<'a> Parser<'a> {
     /// Recursive decent parser ///
     /// precendence rule:
-    ///  1. primary : number | string | true | false | nil | ( expr )
-    ///  2. unary -> ! | -
-    ///  3. factor -> / | *
-    ///  4. term -> - | +
-    ///  5. comparision -> < | <= | > | >=
-    ///  6. equality -> != | ==
-
+    ///  1. primary : number | string | true | false | nil | this | identifier | ( expr ) |
+    ///     super.identifier
+    ///  2. call -> primary ( args ) | primary.identifier
+    ///  3. unary -> ! | -
+    ///  4. factor -> / | *
+    ///  5. term -> - | +
+    ///  6. comparision -> < | <= | > | >=
+    ///  7. equality -> != | ==
+    ///  8. logic_and -> and
+    ///  9. logic_or -> or
+    /// 10. assignment -> ( call . )? identifier = assignment
     pub fn new(tokens: &'a Vec<Token>) -> Self {
         Parser { tokens, current: 0 }
     }
@@ -46,41 +56,272 @@ impl<'a> Parser<'a> {
     pub fn parse(&mut self) -> Vec<Stmt> {
         let mut stmts: Vec<Stmt> = vec![];
         while !self.is_at_end() {
-            if self.match_token(vec![TokenType::Print]) {
-                match self.statement() {
-                    Ok(result) => {
-                        stmts.push(Stmt::PrintStmt(result));
-                    }
-                    Err(e) => {
-                        println!("{e}");
-                    }
+            match self.declaration() {
+                Ok(result) => {
+                    stmts.push(result);
                 }
-            } else {
-                match self.statement() {
-                    Ok(result) => {
-                        stmts.push(Stmt::ExprStmt(result));
-                    }
-                    Err(e) => {
-                        println!("{e}");
-                    }
+                Err(e) => {
+                    println!("{e}");
+                    self.synchronize();
                 }
             }
         }
         stmts
     }
 
-    fn statement(&mut self) -> Result<Box<dyn Expr>, ParserError> {
-        let expr = self.expression();
-        if !self.consume(TokenType::Semicolon) {
-            return Err(self.build_parser_error(
-                self.peek(),
-                "statement must end with semicolon ';'".to_string(),
-            ));
+    fn declaration(&mut self) -> Result<Stmt, ParserError> {
+        // declaration -> class_declaration | fun_declaration | var_declaration | statement
+        if self.match_token(vec![TokenType::Class]) {
+            return self.class_declaration();
+        }
+        if self.match_token(vec![TokenType::Fun]) {
+            return Ok(Stmt::Function(Rc::new(self.function("function")?)));
+        }
+        if self.match_token(vec![TokenType::Var]) {
+            return self.var_declaration();
+        }
+        self.statement()
+    }
+
+    fn class_declaration(&mut self) -> Result<Stmt, ParserError> {
+        // class_declaration -> 'class' IDENTIFIER ( '<' IDENTIFIER )? '{' function* '}'
+        let name = self.expect(TokenType::Identifier, "expected a class name".to_string())?;
+        let superclass = if self.match_token(vec![TokenType::Less]) {
+            let superclass_name = self.expect(
+                TokenType::Identifier,
+                "expected a superclass name".to_string(),
+            )?;
+            Some(Identifier::new(superclass_name))
+        } else {
+            None
+        };
+        self.expect(
+            TokenType::LeftBrace,
+            "expected '{' before the class body".to_string(),
+        )?;
+        let mut methods: Vec<Rc<Function>> = vec![];
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(Rc::new(self.function("method")?));
+        }
+        self.expect(
+            TokenType::RightBrace,
+            "expected '}' after the class body".to_string(),
+        )?;
+        Ok(Stmt::Class {
+            name,
+            superclass,
+            methods,
+        })
+    }
+
+    fn function(&mut self, kind: &str) -> Result<Function, ParserError> {
+        // function -> IDENTIFIER '(' parameters? ')' block
+        // parameters -> IDENTIFIER ( ',' IDENTIFIER )*
+        let name = self.expect(TokenType::Identifier, format!("expected a {kind} name"))?;
+        self.expect(
+            TokenType::LeftParen,
+            format!("expected '(' after the {kind} name"),
+        )?;
+        let mut params: Vec<Token> = vec![];
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if params.len() >= MAX_ARGUMENTS {
+                    return Err(self.build_parser_error(
+                        self.peek(),
+                        format!("can't have more than {MAX_ARGUMENTS} parameters"),
+                    ));
+                }
+                params.push(self.expect(
+                    TokenType::Identifier,
+                    "expected a parameter name".to_string(),
+                )?);
+                if !self.match_token(vec![TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.expect(
+            TokenType::RightParen,
+            "expected ')' after parameters".to_string(),
+        )?;
+        self.expect(
+            TokenType::LeftBrace,
+            format!("expected '{{' before the {kind} body"),
+        )?;
+        let body = self.block()?;
+        Ok(Function { name, params, body })
+    }
+
+    fn var_declaration(&mut self) -> Result<Stmt, ParserError> {
+        // var_declaration -> 'var' IDENTIFIER ( '=' expression )? ';'
+        let name = self.expect(
+            TokenType::Identifier,
+            "expected a variable name".to_string(),
+        )?;
+        let initializer = if self.match_token(vec![TokenType::Equal]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.expect(
+            TokenType::Semicolon,
+            "variable declaration must end with semicolon ';'".to_string(),
+        )?;
+        Ok(Stmt::Var { name, initializer })
+    }
+
+    fn statement(&mut self) -> Result<Stmt, ParserError> {
+        // statement -> print_statement | if_statement | while_statement | for_statement
+        //              | return_statement | block | expression_statement
+        if self.match_token(vec![TokenType::If]) {
+            return self.if_statement();
         }
-        match expr {
-            Ok(result) => Ok(result),
-            Err(parser_error) => Err(parser_error),
+        if self.match_token(vec![TokenType::Return]) {
+            return self.return_statement();
         }
+        if self.match_token(vec![TokenType::While]) {
+            return self.while_statement();
+        }
+        if self.match_token(vec![TokenType::For]) {
+            return self.for_statement();
+        }
+        if self.match_token(vec![TokenType::Print]) {
+            return Ok(Stmt::Print(self.expression_statement()?));
+        }
+        if self.match_token(vec![TokenType::LeftBrace]) {
+            return Ok(Stmt::Block(self.block()?));
+        }
+        Ok(Stmt::Expression(self.expression_statement()?))
+    }
+
+    fn if_statement(&mut self) -> Result<Stmt, ParserError> {
+        // if_statement -> 'if' '(' expression ')' statement ( 'else' statement )?
+        self.expect(TokenType::LeftParen, "expected '(' after 'if'".to_string())?;
+        let condition = self.expression()?;
+        self.expect(
+            TokenType::RightParen,
+            "expected ')' after if condition".to_string(),
+        )?;
+        let then_branch = Box::new(self.statement()?);
+        // a dangling else binds to the nearest if
+        let else_branch = if self.match_token(vec![TokenType::Else]) {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        Ok(Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+
+    fn return_statement(&mut self) -> Result<Stmt, ParserError> {
+        // return_statement -> 'return' expression? ';'
+        let keyword = self.previous();
+        let value = if self.check(&TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.expect(
+            TokenType::Semicolon,
+            "return statement must end with semicolon ';'".to_string(),
+        )?;
+        Ok(Stmt::Return { keyword, value })
+    }
+
+    fn while_statement(&mut self) -> Result<Stmt, ParserError> {
+        // while_statement -> 'while' '(' expression ')' statement
+        self.expect(
+            TokenType::LeftParen,
+            "expected '(' after 'while'".to_string(),
+        )?;
+        let condition = self.expression()?;
+        self.expect(
+            TokenType::RightParen,
+            "expected ')' after while condition".to_string(),
+        )?;
+        let body = Box::new(self.statement()?);
+        Ok(Stmt::While { condition, body })
+    }
+
+    fn for_statement(&mut self) -> Result<Stmt, ParserError> {
+        // for_statement -> 'for' '(' ( var_declaration | expression_statement | ';' )
+        //                  expression? ';' expression? ')' statement
+        //
+        // desugared into: { initializer; while (condition) { body; increment; } }
+        self.expect(TokenType::LeftParen, "expected '(' after 'for'".to_string())?;
+        let initializer = if self.match_token(vec![TokenType::Semicolon]) {
+            None
+        } else if self.match_token(vec![TokenType::Var]) {
+            Some(self.var_declaration()?)
+        } else {
+            Some(Stmt::Expression(self.expression_statement()?))
+        };
+        let condition: Box<dyn Expr> = if self.check(&TokenType::Semicolon) {
+            // a missing condition loops forever
+            let line = self.peek().line;
+            Box::new(Literal::new(Token::new(
+                TokenType::True,
+                "true".to_string(),
+                line,
+            )))
+        } else {
+            self.expression()?
+        };
+        self.expect(
+            TokenType::Semicolon,
+            "expected ';' after for loop condition".to_string(),
+        )?;
+        let increment = if self.check(&TokenType::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.expect(
+            TokenType::RightParen,
+            "expected ')' after for clauses".to_string(),
+        )?;
+
+        let mut body = self.statement()?;
+        if let Some(increment) = increment {
+            body = Stmt::Block(vec![body, Stmt::Expression(increment)]);
+        }
+        body = Stmt::While {
+            condition,
+            body: Box::new(body),
+        };
+        if let Some(initializer) = initializer {
+            body = Stmt::Block(vec![initializer, body]);
+        }
+        Ok(body)
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, ParserError> {
+        // block -> '{' declaration* '}'
+        let mut stmts: Vec<Stmt> = vec![];
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            stmts.push(self.declaration()?);
+        }
+        self.expect(TokenType::RightBrace, "block must end with '}'".to_string())?;
+        Ok(stmts)
+    }
+
+    fn expression_statement(&mut self) -> Result<Box<dyn Expr>, ParserError> {
+        let expr = self.expression()?;
+        self.expect(
+            TokenType::Semicolon,
+            "statement must end with semicolon ';'".to_string(),
+        )?;
+        Ok(expr)
+    }
+
+    fn expect(&mut self, to_consume: TokenType, message: String) -> Result<Token, ParserError> {
+        if self.consume(to_consume) {
+            return Ok(self.previous());
+        }
+        Err(self.build_parser_error(self.peek(), message))
     }
 
     fn consume(&mut self, to_consume: TokenType) -> bool {
@@ -116,10 +357,7 @@ impl<'a> Parser<'a> {
     }
 
     fn is_at_end(&self) -> bool {
-        match self.peek().token_type {
-            TokenType::Eof => true,
-            _ => false,
-        }
+        matches!(self.peek().token_type, TokenType::Eof)
     }
 
     fn peek(&self) -> &Token {
@@ -131,15 +369,57 @@ impl<'a> Parser<'a> {
     }
 
     fn expression(&mut self) -> Result<Box<dyn Expr>, ParserError> {
-        self.equality()
+        self.assignment()
+    }
+
+    fn assignment(&mut self) -> Result<Box<dyn Expr>, ParserError> {
+        // assignment -> ( call '.' )? IDENTIFIER '=' assignment | logic_or
+        let expr = self.or()?;
+        if self.match_token(vec![TokenType::Equal]) {
+            let equals = self.previous();
+            let value = self.assignment()?;
+            let expr = expr.into_any();
+            if expr.is::<Identifier>() {
+                let target = expr.downcast::<Identifier>().unwrap();
+                return Ok(Box::new(Assign::new(target.name, value)));
+            }
+            if expr.is::<Get>() {
+                let target = expr.downcast::<Get>().unwrap();
+                return Ok(Box::new(Set::new(target.object, target.name, value)));
+            }
+            return Err(self.build_parser_error(&equals, "Invalid assignment target".to_string()));
+        }
+        Ok(expr)
+    }
+
+    fn or(&mut self) -> Result<Box<dyn Expr>, ParserError> {
+        // logic_or -> logic_and ( 'or' logic_and )*
+        let mut expr = self.and()?;
+        while self.match_token(vec![TokenType::Or]) {
+            let operator = Operator::new(self.previous());
+            let right = self.and()?;
+            expr = Box::new(Logical::new(expr, right, operator));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Box<dyn Expr>, ParserError> {
+        // logic_and -> equality ( 'and' equality )*
+        let mut expr = self.equality()?;
+        while self.match_token(vec![TokenType::And]) {
+            let operator = Operator::new(self.previous());
+            let right = self.equality()?;
+            expr = Box::new(Logical::new(expr, right, operator));
+        }
+        Ok(expr)
     }
 
     fn equality(&mut self) -> Result<Box<dyn Expr>, ParserError> {
         // equality -> comparison (('!=' | '==') comparison) *
-        let mut expr = self.comparison().unwrap();
+        let mut expr = self.comparison()?;
         while self.match_token(vec![TokenType::BangEqual, TokenType::EqualEqual]) {
             let operator = Operator::new(self.previous());
-            let right = self.comparison().unwrap();
+            let right = self.comparison()?;
             expr = Box::new(Binary::new(expr, right, operator));
         }
         Ok(expr)
@@ -147,7 +427,7 @@ impl<'a> Parser<'a> {
 
     fn comparison(&mut self) -> Result<Box<dyn Expr>, ParserError> {
         // comparison -> term ( ( > | >= | < | <= ) term)*
-        let mut expr = self.term().unwrap();
+        let mut expr = self.term()?;
         while self.match_token(vec![
             TokenType::Greater,
             TokenType::GreaterEqual,
@@ -155,7 +435,7 @@ impl<'a> Parser<'a> {
             TokenType::LessEqual,
         ]) {
             let operator = Operator::new(self.previous());
-            let right = self.term().unwrap();
+            let right = self.term()?;
             expr = Box::new(Binary::new(expr, right, operator));
         }
         Ok(expr)
@@ -163,10 +443,10 @@ impl<'a> Parser<'a> {
 
     fn term(&mut self) -> Result<Box<dyn Expr>, ParserError> {
         // term -> factor (( - | +) factor)*
-        let mut expr = self.factor().unwrap();
+        let mut expr = self.factor()?;
         while self.match_token(vec![TokenType::Minus, TokenType::Plus]) {
             let operator = Operator::new(self.previous());
-            let right = self.factor().unwrap();
+            let right = self.factor()?;
             expr = Box::new(Binary::new(expr, right, operator));
         }
         Ok(expr)
@@ -174,27 +454,71 @@ impl<'a> Parser<'a> {
 
     fn factor(&mut self) -> Result<Box<dyn Expr>, ParserError> {
         // factor -> unary ((/ | *) unary)*
-        let mut expr = self.unary().unwrap();
+        let mut expr = self.unary()?;
         while self.match_token(vec![TokenType::Slash, TokenType::Star]) {
             let operator = Operator::new(self.previous());
-            let right = self.unary().unwrap();
+            let right = self.unary()?;
             expr = Box::new(Binary::new(expr, right, operator));
         }
         Ok(expr)
     }
 
     fn unary(&mut self) -> Result<Box<dyn Expr>, ParserError> {
-        // unary -> ('!' | '-') unary | primary
+        // unary -> ('!' | '-') unary | call
         if self.match_token(vec![TokenType::Bang, TokenType::Minus]) {
             let operator = Operator::new(self.previous());
-            let right = self.unary().unwrap();
+            let right = self.unary()?;
             return Ok(Box::new(Unary::new(operator, right)));
         }
-        self.primary()
+        self.call()
+    }
+
+    fn call(&mut self) -> Result<Box<dyn Expr>, ParserError> {
+        // call -> primary ( '(' arguments? ')' | '.' IDENTIFIER )*
+        let mut expr = self.primary()?;
+        loop {
+            if self.match_token(vec![TokenType::LeftParen]) {
+                expr = self.finish_call(expr)?;
+            } else if self.match_token(vec![TokenType::Dot]) {
+                let name = self.expect(
+                    TokenType::Identifier,
+                    "expected a property name after '.'".to_string(),
+                )?;
+                expr = Box::new(Get::new(expr, name));
+            } else {
+                break;
+            }
+        }
+        Ok(expr)
+    }
+
+    fn finish_call(&mut self, callee: Box<dyn Expr>) -> Result<Box<dyn Expr>, ParserError> {
+        // arguments -> expression ( ',' expression )*
+        let mut arguments: Vec<Box<dyn Expr>> = vec![];
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                if arguments.len() >= MAX_ARGUMENTS {
+                    return Err(self.build_parser_error(
+                        self.peek(),
+                        format!("can't have more than {MAX_ARGUMENTS} arguments"),
+                    ));
+                }
+                arguments.push(self.expression()?);
+                if !self.match_token(vec![TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let paren = self.expect(
+            TokenType::RightParen,
+            "expected ')' after arguments".to_string(),
+        )?;
+        Ok(Box::new(Call::new(callee, paren, arguments)))
     }
 
     fn primary(&mut self) -> Result<Box<dyn Expr>, ParserError> {
-        // primary -> NUMBER | STRING | 'true' | 'false' | 'nil' | '(' expression ')'
+        // primary -> NUMBER | STRING | 'true' | 'false' | 'nil' | 'this' | IDENTIFIER
+        //            | '(' expression ')' | 'super' '.' IDENTIFIER
         if self.match_token(vec![
             TokenType::Number,
             TokenType::String,
@@ -204,8 +528,23 @@ impl<'a> Parser<'a> {
         ]) {
             return Ok(Box::new(Literal::new(self.previous())));
         }
+        if self.match_token(vec![TokenType::Super]) {
+            let keyword = self.previous();
+            self.expect(TokenType::Dot, "expected '.' after 'super'".to_string())?;
+            let method = self.expect(
+                TokenType::Identifier,
+                "expected a superclass method name".to_string(),
+            )?;
+            return Ok(Box::new(Super::new(keyword, method)));
+        }
+        if self.match_token(vec![TokenType::This]) {
+            return Ok(Box::new(This::new(self.previous())));
+        }
+        if self.match_token(vec![TokenType::Identifier]) {
+            return Ok(Box::new(Identifier::new(self.previous())));
+        }
         if self.match_token(vec![TokenType::LeftParen]) {
-            let expr = self.expression().unwrap();
+            let expr = self.expression()?;
             // consume the matching bracket after that
             if self.consume(TokenType::RightParen) {
                 return Ok(Box::new(Grouping::new(expr)));
@@ -214,7 +553,7 @@ impl<'a> Parser<'a> {
                 self.build_parser_error(self.peek(), "No matching bracket for (".to_string())
             );
         }
-        Err(self.build_parser_error(&self.previous(), "Invalid expression".to_string()))
+        Err(self.build_parser_error(self.peek(), "Invalid expression".to_string()))
     }
 
     fn build_parser_error(&self, token: &Token, message: String) -> ParserError {
@@ -237,7 +576,7 @@ impl<'a> Parser<'a> {
         // to avoid reporting false errors
         self.advance();
         while !self.is_at_end() {
-            if self.previous().token_type == TokenType::Eof {
+            if self.previous().token_type == TokenType::Semicolon {
                 return;
             }
             match self.peek().token_type {
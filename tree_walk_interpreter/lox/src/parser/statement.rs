@@ -1,6 +1,42 @@
+use std::rc::Rc;
+
+use super::expression::Identifier;
 use super::Expr;
+use crate::scanner::token::Token;
 
 pub enum Stmt {
-    ExprStmt(Box<dyn Expr>),
-    PrintStmt(Box<dyn Expr>),
+    Expression(Box<dyn Expr>),
+    Print(Box<dyn Expr>),
+    Var {
+        name: Token,
+        initializer: Option<Box<dyn Expr>>,
+    },
+    Block(Vec<Stmt>),
+    If {
+        condition: Box<dyn Expr>,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    /// `for` loops are desugared into a `While` as well
+    While {
+        condition: Box<dyn Expr>,
+        body: Box<Stmt>,
+    },
+    /// shared with the function values created from it, which can outlive the statement
+    Function(Rc<Function>),
+    Return {
+        keyword: Token,
+        value: Option<Box<dyn Expr>>,
+    },
+    Class {
+        name: Token,
+        superclass: Option<Identifier>,
+        methods: Vec<Rc<Function>>,
+    },
+}
+
+pub struct Function {
+    pub name: Token,
+    pub params: Vec<Token>,
+    pub body: Vec<Stmt>,
 }
@@ -2,7 +2,9 @@ use std::any::Any;
 use std::error::Error;
 use std::vec;
 
-use crate::parser::expression::{Binary, Grouping, Literal, Unary};
+use crate::parser::expression::{
+    Assign, Binary, Call, Get, Grouping, Identifier, Literal, Logical, Set, Super, This, Unary,
+};
 use crate::parser::expression::{Expr, ExpressionVisitor};
 
 pub struct AstPrinterVisitor {}
@@ -13,10 +15,16 @@ impl AstPrinterVisitor {
     }
 }
 
-fn parenthesize(name: String, exprs: Vec<&Box<dyn Expr>>) -> String {
+impl Default for AstPrinterVisitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn parenthesize(name: String, exprs: Vec<&dyn Expr>) -> String {
     let mut sub_rslts: Vec<String> = vec![];
     for expr in exprs {
-        let rslt = (*expr).accept(Box::new(AstPrinterVisitor::new())).unwrap();
+        let rslt = expr.accept(Box::new(AstPrinterVisitor::new())).unwrap();
         let rslt = *rslt.downcast::<String>().unwrap();
         sub_rslts.push(rslt);
     }
@@ -25,23 +33,58 @@ fn parenthesize(name: String, exprs: Vec<&Box<dyn Expr>>) -> String {
 
 impl ExpressionVisitor for AstPrinterVisitor {
     fn for_unary(&self, expr: &Unary) -> Result<Box<dyn Any>, Box<dyn Error>> {
-        let name = (*expr).operator.token.lexeme.clone();
-        let right = &(*expr).right;
-        Ok(Box::new(parenthesize(name, vec![right])))
+        let name = expr.operator.token.lexeme.clone();
+        Ok(Box::new(parenthesize(name, vec![&*expr.right])))
     }
     fn for_binary(&self, expr: &Binary) -> Result<Box<dyn Any>, Box<dyn Error>> {
-        let name = (*expr).operator.token.lexeme.clone();
-        let left = &expr.left;
-        let right = &expr.right;
-        Ok(Box::new(parenthesize(name, vec![left, right])))
+        let name = expr.operator.token.lexeme.clone();
+        Ok(Box::new(parenthesize(
+            name,
+            vec![&*expr.left, &*expr.right],
+        )))
     }
     fn for_literal(&self, expr: &Literal) -> Result<Box<dyn Any>, Box<dyn Error>> {
         Ok(Box::new(expr.token.lexeme.clone()))
     }
     fn for_grouping(&self, expr: &Grouping) -> Result<Box<dyn Any>, Box<dyn Error>> {
         let name = "group".to_owned();
-        let expr = &expr.expr;
-        Ok(Box::new(parenthesize(name, vec![expr])))
+        Ok(Box::new(parenthesize(name, vec![&*expr.expr])))
+    }
+    fn for_identifier(&self, expr: &Identifier) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        Ok(Box::new(expr.name.lexeme.clone()))
+    }
+    fn for_assign(&self, expr: &Assign) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let name = format!("= {}", expr.name.lexeme);
+        Ok(Box::new(parenthesize(name, vec![&*expr.value])))
+    }
+    fn for_call(&self, expr: &Call) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let mut exprs: Vec<&dyn Expr> = vec![&*expr.callee];
+        exprs.extend(expr.arguments.iter().map(|argument| &**argument));
+        Ok(Box::new(parenthesize("call".to_owned(), exprs)))
+    }
+    fn for_get(&self, expr: &Get) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let name = format!(". {}", expr.name.lexeme);
+        Ok(Box::new(parenthesize(name, vec![&*expr.object])))
+    }
+    fn for_set(&self, expr: &Set) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let name = format!("= {}", expr.name.lexeme);
+        Ok(Box::new(parenthesize(
+            name,
+            vec![&*expr.object, &*expr.value],
+        )))
+    }
+    fn for_this(&self, expr: &This) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        Ok(Box::new(expr.keyword.lexeme.clone()))
+    }
+    fn for_super(&self, expr: &Super) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        Ok(Box::new(format!("(super {})", expr.method.lexeme)))
+    }
+    fn for_logical(&self, expr: &Logical) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let name = expr.operator.token.lexeme.clone();
+        Ok(Box::new(parenthesize(
+            name,
+            vec![&*expr.left, &*expr.right],
+        )))
     }
 }
 
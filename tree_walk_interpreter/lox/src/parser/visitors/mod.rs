@@ -1,2 +1,3 @@
 pub mod ast_printer;
 pub mod interpreter;
+pub mod resolver;
@@ -0,0 +1,454 @@
+use core::fmt;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::error::Error;
+use std::rc::Rc;
+
+use crate::parser::expression::{
+    Assign, Binary, Call, Expr, ExpressionVisitor, Get, Grouping, Identifier, Literal, Logical,
+    Set, Super, This, Unary,
+};
+use crate::parser::statement::{Function, Stmt};
+use crate::scanner::token::Token;
+
+#[derive(Debug)]
+struct ResolverError {
+    message: String,
+}
+impl Error for ResolverError {}
+impl fmt::Display for ResolverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[ResolverError] {}", self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+/// Static pass run between parsing and interpreting.
+/// It stores in every variable usage how many scopes away its declaration is,
+/// so that a closure keeps referring to the binding visible where it was declared.
+///
+/// Only local scopes are tracked, variables not found in them are globals.
+/// Just like the interpreter, clones share the same state.
+#[derive(Clone)]
+pub struct Resolver {
+    /// variable name -> whether its initializer is already resolved
+    scopes: Rc<RefCell<Vec<HashMap<String, bool>>>>,
+    current_function: Rc<Cell<FunctionType>>,
+    current_class: Rc<Cell<ClassType>>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Resolver {
+            scopes: Rc::new(RefCell::new(vec![])),
+            current_function: Rc::new(Cell::new(FunctionType::None)),
+            current_class: Rc::new(Cell::new(ClassType::None)),
+        }
+    }
+
+    /// reports the static errors and returns whether the program is safe to run
+    pub fn resolve(&self, stmts: &[Stmt]) -> bool {
+        let errors = self.static_errors(stmts);
+        for e in &errors {
+            println!("{e}");
+        }
+        errors.is_empty()
+    }
+
+    /// the first error of every top level statement, resolving carries on with the next one
+    fn static_errors(&self, stmts: &[Stmt]) -> Vec<Box<dyn Error>> {
+        stmts
+            .iter()
+            .filter_map(|stmt| self.resolve_stmt(stmt).err())
+            .collect()
+    }
+
+    fn resolve_stmts(&self, stmts: &Vec<Stmt>) -> Result<(), Box<dyn Error>> {
+        for stmt in stmts {
+            self.resolve_stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn resolve_stmt(&self, stmt: &Stmt) -> Result<(), Box<dyn Error>> {
+        match stmt {
+            Stmt::Expression(expr) | Stmt::Print(expr) => {
+                self.resolve_expr(&**expr)?;
+            }
+            Stmt::Var { name, initializer } => {
+                // declared and defined separately to catch `var a = a;`
+                self.declare(name)?;
+                if let Some(initializer) = initializer {
+                    self.resolve_expr(&**initializer)?;
+                }
+                self.define(name);
+            }
+            Stmt::Block(stmts) => {
+                self.begin_scope();
+                let result = self.resolve_stmts(stmts);
+                self.end_scope();
+                result?;
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve_expr(&**condition)?;
+                self.resolve_stmt(then_branch)?;
+                if let Some(else_branch) = else_branch {
+                    self.resolve_stmt(else_branch)?;
+                }
+            }
+            Stmt::While { condition, body } => {
+                self.resolve_expr(&**condition)?;
+                self.resolve_stmt(body)?;
+            }
+            Stmt::Function(declaration) => {
+                // defined eagerly so that the function can refer to itself recursively
+                self.declare(&declaration.name)?;
+                self.define(&declaration.name);
+                self.resolve_function(declaration, FunctionType::Function)?;
+            }
+            Stmt::Return { keyword, value } => {
+                if self.current_function.get() == FunctionType::None {
+                    return Err(build_resolver_error(
+                        keyword,
+                        "can't return from top-level code".to_string(),
+                    ));
+                }
+                if let Some(value) = value {
+                    if self.current_function.get() == FunctionType::Initializer {
+                        return Err(build_resolver_error(
+                            keyword,
+                            "can't return a value from an initializer".to_string(),
+                        ));
+                    }
+                    self.resolve_expr(&**value)?;
+                }
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                methods,
+            } => {
+                self.declare(name)?;
+                self.define(name);
+                let enclosing_class = self.current_class.replace(ClassType::Class);
+                let result = self.resolve_class(name, superclass, methods);
+                self.current_class.set(enclosing_class);
+                result?;
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_class(
+        &self,
+        name: &Token,
+        superclass: &Option<Identifier>,
+        methods: &Vec<Rc<Function>>,
+    ) -> Result<(), Box<dyn Error>> {
+        let superclass = match superclass {
+            Some(superclass) => superclass,
+            None => return self.resolve_methods(methods),
+        };
+        if superclass.name.lexeme == name.lexeme {
+            return Err(build_resolver_error(
+                &superclass.name,
+                "a class can't inherit from itself".to_string(),
+            ));
+        }
+        self.current_class.set(ClassType::Subclass);
+        self.resolve_expr(superclass)?;
+        // mirrors the interpreter's extra environment binding `super`
+        self.begin_scope();
+        if let Some(scope) = self.scopes.borrow_mut().last_mut() {
+            scope.insert("super".to_string(), true);
+        }
+        let result = self.resolve_methods(methods);
+        self.end_scope();
+        result
+    }
+
+    fn resolve_methods(&self, methods: &Vec<Rc<Function>>) -> Result<(), Box<dyn Error>> {
+        // methods are resolved inside an extra scope that binds `this`
+        self.begin_scope();
+        if let Some(scope) = self.scopes.borrow_mut().last_mut() {
+            scope.insert("this".to_string(), true);
+        }
+        let mut result = Ok(());
+        for method in methods {
+            let function_type = if method.name.lexeme == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            result = self.resolve_function(method, function_type);
+            if result.is_err() {
+                break;
+            }
+        }
+        self.end_scope();
+        result
+    }
+
+    fn resolve_function(
+        &self,
+        function: &Function,
+        function_type: FunctionType,
+    ) -> Result<(), Box<dyn Error>> {
+        let enclosing_function = self.current_function.replace(function_type);
+        self.begin_scope();
+        let result = self.resolve_function_body(function);
+        self.end_scope();
+        self.current_function.set(enclosing_function);
+        result
+    }
+
+    fn resolve_function_body(&self, function: &Function) -> Result<(), Box<dyn Error>> {
+        for param in &function.params {
+            self.declare(param)?;
+            self.define(param);
+        }
+        self.resolve_stmts(&function.body)
+    }
+
+    fn resolve_expr(&self, expr: &dyn Expr) -> Result<(), Box<dyn Error>> {
+        expr.accept(Box::new(self.clone()))?;
+        Ok(())
+    }
+
+    fn resolve_local(&self, name: &Token) -> Option<usize> {
+        self.scopes
+            .borrow()
+            .iter()
+            .rev()
+            .position(|scope| scope.contains_key(&name.lexeme))
+    }
+
+    fn begin_scope(&self) {
+        self.scopes.borrow_mut().push(HashMap::new());
+    }
+
+    fn end_scope(&self) {
+        self.scopes.borrow_mut().pop();
+    }
+
+    fn declare(&self, name: &Token) -> Result<(), Box<dyn Error>> {
+        if let Some(scope) = self.scopes.borrow_mut().last_mut() {
+            if scope.contains_key(&name.lexeme) {
+                return Err(build_resolver_error(
+                    name,
+                    "already a variable with this name in this scope".to_string(),
+                ));
+            }
+            scope.insert(name.lexeme.clone(), false);
+        }
+        Ok(())
+    }
+
+    fn define(&self, name: &Token) {
+        if let Some(scope) = self.scopes.borrow_mut().last_mut() {
+            scope.insert(name.lexeme.clone(), true);
+        }
+    }
+}
+
+fn build_resolver_error(token: &Token, message: String) -> Box<dyn Error> {
+    Box::new(ResolverError {
+        message: format!(
+            " [line {}] Error  at '{}': {}",
+            token.line, token.lexeme, message
+        ),
+    })
+}
+
+impl ExpressionVisitor for Resolver {
+    fn for_unary(&self, expr: &Unary) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        self.resolve_expr(&*expr.right)?;
+        Ok(Box::new(()))
+    }
+
+    fn for_binary(&self, expr: &Binary) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        self.resolve_expr(&*expr.left)?;
+        self.resolve_expr(&*expr.right)?;
+        Ok(Box::new(()))
+    }
+
+    fn for_grouping(&self, expr: &Grouping) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        self.resolve_expr(&*expr.expr)?;
+        Ok(Box::new(()))
+    }
+
+    fn for_literal(&self, _expr: &Literal) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        Ok(Box::new(()))
+    }
+
+    fn for_identifier(&self, expr: &Identifier) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        let is_uninitialized = self
+            .scopes
+            .borrow()
+            .last()
+            .and_then(|scope| scope.get(&expr.name.lexeme))
+            == Some(&false);
+        if is_uninitialized {
+            return Err(build_resolver_error(
+                &expr.name,
+                "can't read local variable in its own initializer".to_string(),
+            ));
+        }
+        expr.depth.set(self.resolve_local(&expr.name));
+        Ok(Box::new(()))
+    }
+
+    fn for_assign(&self, expr: &Assign) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        self.resolve_expr(&*expr.value)?;
+        expr.depth.set(self.resolve_local(&expr.name));
+        Ok(Box::new(()))
+    }
+
+    fn for_get(&self, expr: &Get) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        // properties are looked up dynamically, only the object is resolved
+        self.resolve_expr(&*expr.object)?;
+        Ok(Box::new(()))
+    }
+
+    fn for_set(&self, expr: &Set) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        self.resolve_expr(&*expr.value)?;
+        self.resolve_expr(&*expr.object)?;
+        Ok(Box::new(()))
+    }
+
+    fn for_this(&self, expr: &This) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        if self.current_class.get() == ClassType::None {
+            return Err(build_resolver_error(
+                &expr.keyword,
+                "can't use 'this' outside of a class".to_string(),
+            ));
+        }
+        expr.depth.set(self.resolve_local(&expr.keyword));
+        Ok(Box::new(()))
+    }
+
+    fn for_super(&self, expr: &Super) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        match self.current_class.get() {
+            ClassType::None => Err(build_resolver_error(
+                &expr.keyword,
+                "can't use 'super' outside of a class".to_string(),
+            )),
+            ClassType::Class => Err(build_resolver_error(
+                &expr.keyword,
+                "can't use 'super' in a class with no superclass".to_string(),
+            )),
+            ClassType::Subclass => {
+                expr.depth.set(self.resolve_local(&expr.keyword));
+                Ok(Box::new(()))
+            }
+        }
+    }
+
+    fn for_logical(&self, expr: &Logical) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        self.resolve_expr(&*expr.left)?;
+        self.resolve_expr(&*expr.right)?;
+        Ok(Box::new(()))
+    }
+
+    fn for_call(&self, expr: &Call) -> Result<Box<dyn Any>, Box<dyn Error>> {
+        self.resolve_expr(&*expr.callee)?;
+        for argument in &expr.arguments {
+            self.resolve_expr(&**argument)?;
+        }
+        Ok(Box::new(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::{parser::Parser, scanner::Scanner, AstInterpreterVisitor};
+
+    use super::Resolver;
+
+    fn static_errors(source: &str) -> Vec<String> {
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens();
+        let statements = Parser::new(tokens).parse();
+        Resolver::new()
+            .static_errors(&statements)
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    const INVALID_PROGRAMS: [(&str, &str); 7] = [
+        (
+            "{ var a = 1; { var a = a; } }",
+            "can't read local variable in its own initializer",
+        ),
+        ("return 1;", "can't return from top-level code"),
+        ("print this;", "can't use 'this' outside of a class"),
+        (
+            "fun f() { return this; }",
+            "can't use 'this' outside of a class",
+        ),
+        ("print super.m;", "can't use 'super' outside of a class"),
+        (
+            "class A { m() { return super.m(); } }",
+            "can't use 'super' in a class with no superclass",
+        ),
+        ("class A < A {}", "a class can't inherit from itself"),
+    ];
+
+    #[test]
+    fn reports_static_errors() {
+        for (source, message) in INVALID_PROGRAMS {
+            let errors = static_errors(source);
+            assert_eq!(errors.len(), 1, "{source}: {errors:?}");
+            assert!(errors[0].contains(message), "{source}: {errors:?}");
+        }
+        let valid = "var a = 1; { var b = a; } fun f() { return a; }
+            class A { m() { return this; } }
+            class B < A { m() { return super.m(); } }";
+        assert!(static_errors(valid).is_empty());
+    }
+
+    #[test]
+    fn static_errors_prevent_execution() {
+        let interpreter = AstInterpreterVisitor::new();
+        let ran = Rc::new(Cell::new(false));
+        let flag = ran.clone();
+        interpreter.register_native(
+            "run",
+            0,
+            Box::new(move |_| {
+                flag.set(true);
+                Ok(Box::new(()))
+            }),
+        );
+        for (source, _) in INVALID_PROGRAMS {
+            // the call comes before the error, nothing runs when any statement is invalid
+            crate::run_with(&interpreter, &format!("run(); {source}"));
+            assert!(!ran.get(), "{source}");
+        }
+        crate::run_with(&interpreter, "run();");
+        assert!(ran.get());
+    }
+}
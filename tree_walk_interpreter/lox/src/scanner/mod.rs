@@ -13,7 +13,7 @@ pub struct Scanner<'a> {
     line: usize,
 }
 impl Scanner<'_> {
-    pub fn new(source: &String) -> Scanner {
+    pub fn new(source: &str) -> Scanner<'_> {
         Scanner {
             source: source.chars().peekable(),
             tokens: vec![],
@@ -21,7 +21,7 @@ impl Scanner<'_> {
         }
     }
     pub fn scan_tokens(&mut self) -> &Vec<Token> {
-        while self.source.peek() != None {
+        while self.source.peek().is_some() {
             self.scan_token();
         }
         self.add_token(TokenType::Eof, String::new());
@@ -126,7 +126,7 @@ impl Scanner<'_> {
                     lexeme.push('\n');
                 }
                 Some(c) => {
-                    lexeme.push(c.clone());
+                    lexeme.push(c);
                 }
                 None => {
                     error::report(
@@ -145,12 +145,12 @@ impl Scanner<'_> {
         let mut decimal_read = false;
         loop {
             match self.source.peek() {
-                Some(c) if Self::is_digit(c.clone()) => {
-                    lexeme.push(c.clone());
+                Some(c) if Self::is_digit(*c) => {
+                    lexeme.push(*c);
                     self.source.next();
                 }
                 Some('.') if !decimal_read => match self.peek_skip_ahead() {
-                    Some(c) if Self::is_digit(c.clone()) => {
+                    Some(c) if Self::is_digit(c) => {
                         lexeme.push('.');
                         decimal_read = true;
                         self.source.next();
@@ -169,8 +169,8 @@ impl Scanner<'_> {
         let mut lexeme = String::from(starting_char);
         loop {
             match self.source.peek() {
-                Some(c) if Self::is_alphabetic(c.clone()) || Self::is_digit(c.clone()) => {
-                    lexeme.push(c.clone());
+                Some(c) if Self::is_alphabetic(*c) || Self::is_digit(*c) => {
+                    lexeme.push(*c);
                     self.source.next();
                 }
                 _ => {
@@ -215,7 +215,7 @@ impl Scanner<'_> {
         unmatch_lexeme: String,
     ) {
         match self.source.peek() {
-            Some(c) if c.clone() == to_match => {
+            Some(c) if *c == to_match => {
                 self.source.next();
                 self.add_token(match_token, match_lexeme);
             }
@@ -236,17 +236,11 @@ impl Scanner<'_> {
     }
 
     fn is_digit(ch: char) -> bool {
-        match ch {
-            '0' | '1' | '2' | '3' | '4' | '5' | '6' | '7' | '8' | '9' => true,
-            _ => false,
-        }
+        ch.is_ascii_digit()
     }
 
     fn is_alphabetic(ch: char) -> bool {
-        match ch {
-            'a'..='z' | 'A'..='Z' => true,
-            _ => false,
-        }
+        ch.is_ascii_alphabetic() || ch == '_'
     }
 }
 
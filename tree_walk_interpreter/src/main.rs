@@ -3,8 +3,6 @@ use std::fs;
 use std::io::Write;
 use std::process;
 
-use lox_core;
-
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() > 2 {
@@ -22,7 +20,7 @@ fn run_file(file_path: &String) {
     let file_content = fs::read_to_string(file_path);
     match file_content {
         Err(e) => {
-            println!("{}. {}", file_path, e.to_string());
+            println!("{}. {}", file_path, e);
             process::exit(65);
         }
         Ok(content) => lox_core::run(&content),
@@ -31,6 +29,8 @@ fn run_file(file_path: &String) {
 
 fn run_prompt() {
     println!("Lox REPL (enter exit() / q to exit)");
+    // shared by every line so that later lines see the earlier declarations
+    let interpreter = lox_core::AstInterpreterVisitor::new();
     loop {
         print!("> ");
         let mut input = String::new();
@@ -39,12 +39,12 @@ fn run_prompt() {
             .read_line(&mut input)
             .expect("can not read user input");
 
-        if input.trim() == String::from("exit()") {
+        if input.trim() == "exit()" {
             break;
         }
-        if input.trim() == String::from("q") {
+        if input.trim() == "q" {
             break;
         }
-        lox_core::run(&input);
+        lox_core::run_with(&interpreter, &input);
     }
 }